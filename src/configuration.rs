@@ -1,17 +1,51 @@
 use serde::Deserialize;
 
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::fs;
 use std::path::Path;
+use std::time::Duration;
 
-#[derive(Default, Deserialize)]
+#[derive(Clone, Default, Deserialize)]
 pub struct Configuration {
+    adaptor_expiry: Option<u64>,
     bind_address: Option<String>,
+    clock_check_interval: Option<u64>,
+    clock_sync: Option<bool>,
+    clock_sync_threshold: Option<u64>,
+    device_identity: Option<DeviceIdentity>,
+    enum_metrics: Option<EnumMetrics>,
     hosts: Option<Vec<String>>,
+    #[serde(default)]
+    known_firmware: Vec<KnownFirmware>,
+    #[serde(default, rename = "host")]
+    host_overrides: Vec<HostConfiguration>,
+    #[serde(default, rename = "metric")]
+    metrics: Vec<MetricMapping>,
+    #[serde(default)]
+    discover_addresses: Vec<String>,
+    #[serde(default)]
+    discover_allow: Vec<DiscoverRule>,
     discover_bind_address: Option<String>,
+    #[serde(default)]
+    discover_deny: Vec<DiscoverRule>,
+    #[serde(default)]
+    discover_exclude_interfaces: Vec<String>,
+    #[serde(default)]
+    discover_interfaces: Vec<String>,
     discover_major_interval: Option<u64>,
     discover_minor_interval: Option<u64>,
+    discover_passive: Option<bool>,
+    discover_passive_address: Option<String>,
+    #[serde(default)]
+    discover_sweep: Vec<String>,
+    discover_sweep_interval: Option<u64>,
+    #[serde(default, rename = "power_model")]
+    power_models: Vec<PowerModel>,
+    raw_metrics: Option<bool>,
     refresh_interval: Option<u64>,
     refresh_timeout: Option<u64>,
+    state_file: Option<String>,
 }
 
 impl Configuration {
@@ -19,19 +53,30 @@ impl Configuration {
     pub fn load<P: AsRef<Path>>(path: P) -> Self {
         let source = fs::read_to_string(path).unwrap();
 
-        toml::from_str(&source).unwrap()
-    }
+        let configuration: Self = toml::from_str(&source).unwrap();
 
-    // Load configuration from the next argument in the environment.
-    pub fn load_from_next_arg() -> Self {
-        let file = match std::env::args().nth(1) {
-            None => {
-                return Configuration::default();
+        for host in &configuration.host_overrides {
+            if let Some(name) = &host.power_model {
+                if configuration.power_model(name).is_none() {
+                    panic!("No [[power_model]] named {}", name);
+                }
             }
-            Some(f) => f,
-        };
+        }
+
+        configuration
+    }
 
-        Configuration::load(file)
+    // Time a discovered HVAC unit may go unseen by discovery while failing polls before it is no
+    // longer polled.  Defaults to 30 minutes.
+    pub fn adaptor_expiry(&self) -> Duration {
+        let expiry = self.adaptor_expiry.unwrap_or(1_800_000);
+
+        Duration::from_millis(expiry)
+    }
+
+    // Firmware builds known to work.  When empty no firmware is flagged.
+    pub fn known_firmware(&self) -> &[KnownFirmware] {
+        &self.known_firmware
     }
 
     // Bind address for Prometheus metric server
@@ -42,6 +87,49 @@ impl Configuration {
             .to_string()
     }
 
+    // Time between reads of an adaptor's clock.  Defaults to 10 minutes.
+    pub fn clock_check_interval(&self) -> Duration {
+        let interval = self.clock_check_interval.unwrap_or(600_000);
+
+        Duration::from_millis(interval)
+    }
+
+    // Set an adaptor's clock to the host's time when it drifts past the sync threshold.  Defaults
+    // to false.
+    pub fn clock_sync(&self) -> bool {
+        self.clock_sync.unwrap_or(false)
+    }
+
+    // Clock drift allowed before an adaptor's clock is set.  Defaults to 1 minute.
+    pub fn clock_sync_threshold(&self) -> Duration {
+        let threshold = self.clock_sync_threshold.unwrap_or(60_000);
+
+        Duration::from_millis(threshold)
+    }
+
+    // Value of the device label on unit metrics.  Defaults to the name, as before the MAC address
+    // could be used.
+    pub fn device_identity(&self) -> DeviceIdentity {
+        self.device_identity.unwrap_or(DeviceIdentity::Name)
+    }
+
+    // How settings with named values, like the mode, are exported.  Defaults to both numeric
+    // gauges and state sets.
+    pub fn enum_metrics(&self) -> EnumMetrics {
+        self.enum_metrics.unwrap_or(EnumMetrics::Both)
+    }
+
+    // Directed broadcast addresses to send discover requests to in addition to the broadcast
+    // addresses of local interfaces
+    pub fn discover_addresses(&self) -> &[String] {
+        &self.discover_addresses
+    }
+
+    // Rules a discovered unit must match one of to be polled.  When empty all units are allowed.
+    pub fn discover_allow(&self) -> &[DiscoverRule] {
+        &self.discover_allow
+    }
+
     // Bind address for Daikin unit discovery
     pub fn discover_bind_address(&self) -> String {
         self.discover_bind_address
@@ -50,38 +138,438 @@ impl Configuration {
             .to_string()
     }
 
+    // Rules excluding discovered units from polling
+    pub fn discover_deny(&self) -> &[DiscoverRule] {
+        &self.discover_deny
+    }
+
+    // Network interface name globs not to broadcast discover requests from
+    pub fn discover_exclude_interfaces(&self) -> &[String] {
+        &self.discover_exclude_interfaces
+    }
+
+    // Network interface name globs to broadcast discover requests from.  When empty all interfaces
+    // are used.
+    pub fn discover_interfaces(&self) -> &[String] {
+        &self.discover_interfaces
+    }
+
     // Long interval between discover requests.  Defaults to 5 minutes
-    pub fn discover_major_interval(&self) -> std::time::Duration {
+    pub fn discover_major_interval(&self) -> Duration {
         let interval = self.discover_major_interval.unwrap_or(300_000);
 
-        std::time::Duration::from_millis(interval)
+        Duration::from_millis(interval)
     }
 
     // Short interval between discover requests.  Defaults to 200 milliseconds
-    pub fn discover_minor_interval(&self) -> std::time::Duration {
+    pub fn discover_minor_interval(&self) -> Duration {
         let interval = self.discover_minor_interval.unwrap_or(200);
 
-        std::time::Duration::from_millis(interval)
+        Duration::from_millis(interval)
+    }
+
+    // Learn units only from responses to other clients' discover requests, never sending any.
+    // Defaults to false.
+    pub fn discover_passive(&self) -> bool {
+        self.discover_passive.unwrap_or(false)
+    }
+
+    // Address to listen on for discover replies in passive mode.  Units reply to the port the
+    // request came from so this defaults to 0.0.0.0:30000, the ComfortControl app's port.
+    pub fn discover_passive_address(&self) -> String {
+        self.discover_passive_address
+            .as_ref()
+            .unwrap_or(&"0.0.0.0:30000".to_string())
+            .to_string()
+    }
+
+    // Networks (CIDRs) to send unicast discover requests to every address of.  Use this for units
+    // on routed networks.
+    pub fn discover_sweep(&self) -> &[String] {
+        &self.discover_sweep
+    }
+
+    // Interval between unicast discover requests when sweeping a network.  Defaults to 20
+    // milliseconds.
+    pub fn discover_sweep_interval(&self) -> Duration {
+        let interval = self.discover_sweep_interval.unwrap_or(20);
+
+        Duration::from_millis(interval)
+    }
+
+    // Custom metrics exported from adaptor endpoint keys
+    pub fn metrics(&self) -> &[MetricMapping] {
+        &self.metrics
+    }
+
+    // The `[[power_model]]` entry named `name`
+    pub fn power_model(&self, name: &str) -> Option<&PowerModel> {
+        self.power_models.iter().find(|model| model.name == name)
+    }
+
+    // Export every key of every polled endpoint for reverse engineering.  Defaults to false.
+    pub fn raw_metrics(&self) -> bool {
+        self.raw_metrics.unwrap_or(false)
     }
 
     // Interval between HVAC unit data refreshes.  This should be about twice the scrape interval.
     // Defaults to 7.5 seconds.
-    pub fn refresh_interval(&self) -> std::time::Duration {
+    pub fn refresh_interval(&self) -> Duration {
         let interval = self.refresh_interval.unwrap_or(7500);
 
-        std::time::Duration::from_millis(interval)
+        Duration::from_millis(interval)
     }
 
     // Timeout to wait for an HVAC unit to respond.  Defaults to 250ms.
-    pub fn refresh_timeout(&self) -> std::time::Duration {
+    pub fn refresh_timeout(&self) -> Duration {
         let timeout = self.refresh_timeout.unwrap_or(250);
 
-        std::time::Duration::from_millis(timeout)
+        Duration::from_millis(timeout)
+    }
+
+    // File recording discovered units so they are polled immediately after a restart
+    pub fn state_file(&self) -> Option<String> {
+        self.state_file.clone()
     }
 
     // Manually configured hosts.  Set this if UDP discovery is unreliable and you have given all
     // HVAC units static IPs.
     pub fn hosts(&self) -> Option<Vec<String>> {
-        self.hosts.clone()
+        let mut hosts = self.hosts.clone().unwrap_or_default();
+
+        for host in &self.host_overrides {
+            if let Some(address) = &host.address {
+                if !hosts.contains(address) {
+                    hosts.push(address.clone());
+                }
+            }
+        }
+
+        if hosts.is_empty() {
+            None
+        } else {
+            Some(hosts)
+        }
+    }
+
+    // Names of the extra static labels set across all `[[host]]` entries, sorted.
+    pub fn host_label_names(&self) -> Vec<String> {
+        let names: BTreeSet<&String> = self
+            .host_overrides
+            .iter()
+            .flat_map(|host| host.labels.keys())
+            .collect();
+
+        names.into_iter().cloned().collect()
+    }
+
+    // Settings for the HVAC unit at `address`.  A `[[host]]` entry matching the MAC address takes
+    // precedence over one matching the address.
+    pub fn host_settings(&self, address: &str, mac: Option<&str>) -> HostSettings {
+        let by_mac = mac.and_then(|mac| {
+            self.host_overrides.iter().find(|host| match &host.mac {
+                Some(m) => normalize_mac(m) == normalize_mac(mac),
+                None => false,
+            })
+        });
+
+        let host = by_mac.or_else(|| {
+            self.host_overrides
+                .iter()
+                .find(|host| host.address.as_deref() == Some(address))
+        });
+
+        let host = match host {
+            Some(h) => h.clone(),
+            None => HostConfiguration::default(),
+        };
+
+        let refresh_interval = host
+            .refresh_interval
+            .map(Duration::from_millis)
+            .unwrap_or_else(|| self.refresh_interval());
+
+        let refresh_timeout = host
+            .refresh_timeout
+            .map(Duration::from_millis)
+            .unwrap_or_else(|| self.refresh_timeout());
+
+        // Adaptors that need a registration key only accept HTTPS
+        let protocol = host.protocol.unwrap_or(match host.key {
+            Some(_) => Protocol::Https,
+            None => Protocol::Http,
+        });
+
+        let power_model = host
+            .power_model
+            .as_deref()
+            .and_then(|name| self.power_model(name))
+            .cloned();
+
+        HostSettings {
+            name: host.name,
+            labels: host.labels,
+            refresh_interval,
+            refresh_timeout,
+            endpoints: host.endpoints,
+            uuid: host.uuid,
+            lpw: host.lpw,
+            port: host.port,
+            protocol,
+            key: host.key,
+            power_model,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceIdentity {
+    Mac,
+    Name,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EnumMetrics {
+    // daikin_mode 4
+    Numeric,
+    // daikin_mode_state{mode="heat"} 1
+    Stateset,
+    Both,
+}
+
+impl EnumMetrics {
+    pub fn numeric(&self) -> bool {
+        *self != EnumMetrics::Stateset
+    }
+
+    pub fn stateset(&self) -> bool {
+        *self != EnumMetrics::Numeric
+    }
+}
+
+// How the exporter talks to an adaptor.  BRP072C and some BRP069B firmware only accept HTTPS.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Http,
+    Https,
+}
+
+// A `[[known_firmware]]` entry.  Without a `checksum` every build of the `version` matches.
+#[derive(Clone, Default, Deserialize)]
+pub struct KnownFirmware {
+    pub version: String,
+    pub checksum: Option<String>,
+}
+
+impl KnownFirmware {
+    pub fn matches(&self, version: &str, checksum: Option<&str>) -> bool {
+        self.version == version
+            && match &self.checksum {
+                Some(c) => Some(c.as_str()) == checksum,
+                None => true,
+            }
+    }
+}
+
+// A `[[power_model]]` entry estimating the power draw of a unit model from its compressor
+// frequency.  `curve` is a list of `[frequency, watts]` points.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct PowerModel {
+    pub name: String,
+    pub rated_watts: f64,
+    #[serde(default)]
+    pub idle_watts: f64,
+    #[serde(default)]
+    pub curve: Vec<[f64; 2]>,
+}
+
+impl PowerModel {
+    // Power draw of a running unit at compressor `frequency`.  The draw is interpolated between
+    // the curve points, starting from `idle_watts` at 0.  Without a curve it rises linearly to
+    // `rated_watts` at 100.  Estimates never exceed `rated_watts`.
+    pub fn watts(&self, frequency: f64) -> f64 {
+        if frequency <= 0.0 {
+            return self.idle_watts;
+        }
+
+        let mut points = vec![[0.0, self.idle_watts]];
+
+        if self.curve.is_empty() {
+            points.push([100.0, self.rated_watts]);
+        } else {
+            points.extend(self.curve.iter().copied());
+            points.sort_by(|a, b| a[0].total_cmp(&b[0]));
+        }
+
+        let watts = match points.windows(2).find(|pair| frequency <= pair[1][0]) {
+            Some(pair) => {
+                let [[f0, w0], [f1, w1]] = [pair[0], pair[1]];
+
+                if f1 > f0 {
+                    w0 + (w1 - w0) * (frequency - f0) / (f1 - f0)
+                } else {
+                    w1
+                }
+            }
+            None => points.last().unwrap()[1],
+        };
+
+        watts.min(self.rated_watts)
+    }
+}
+
+// A `[[metric]]` entry exporting `key` from the adaptor `endpoint` as the metric `name`.  The
+// value is decoded, looked up in `values` when any are given, then multiplied by `scale`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct MetricMapping {
+    pub endpoint: String,
+    pub key: String,
+    pub name: String,
+    pub help: Option<String>,
+    #[serde(default, rename = "type")]
+    pub metric_type: MetricKind,
+    pub scale: Option<f64>,
+    #[serde(default)]
+    pub decode: Decoding,
+    #[serde(default)]
+    pub values: BTreeMap<String, f64>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricKind {
+    #[default]
+    Gauge,
+    // A counter kept by the adaptor, like ResetCount
+    Counter,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Decoding {
+    #[default]
+    None,
+    // "%41%42" like names
+    Percent,
+    // "4142" like get_monitordata values
+    HexAscii,
+}
+
+// A `[[discover_allow]]` or `[[discover_deny]]` rule.  `name` is a glob and `address` is an IP
+// address or CIDR.
+#[derive(Clone, Default, Deserialize)]
+pub struct DiscoverRule {
+    pub mac: Option<String>,
+    pub name: Option<String>,
+    pub address: Option<String>,
+    pub adaptor_type: Option<String>,
+}
+
+// A `[[host]]` table overriding settings for a single HVAC unit.  Units are matched by `address`
+// or, for discovered units, by `mac`.
+#[derive(Clone, Default, Deserialize)]
+pub struct HostConfiguration {
+    address: Option<String>,
+    mac: Option<String>,
+    name: Option<String>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    refresh_interval: Option<u64>,
+    refresh_timeout: Option<u64>,
+    endpoints: Option<Vec<String>>,
+    uuid: Option<String>,
+    lpw: Option<String>,
+    port: Option<u16>,
+    protocol: Option<Protocol>,
+    key: Option<String>,
+    power_model: Option<String>,
+}
+
+// Settings for a single HVAC unit with `[[host]]` overrides applied to the global defaults.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HostSettings {
+    // Friendly name used instead of the name reported by the unit
+    pub name: Option<String>,
+    // Extra static labels
+    pub labels: BTreeMap<String, String>,
+    pub refresh_interval: Duration,
+    pub refresh_timeout: Duration,
+    // Adaptor endpoints polled after common/basic_info.  None polls the default endpoints for the
+    // adaptor type.
+    pub endpoints: Option<Vec<String>>,
+    // Sent as the X-Daikin-uuid header
+    pub uuid: Option<String>,
+    // Sent as the lpw query parameter
+    pub lpw: Option<String>,
+    // HTTP port when the adaptor doesn't use the default
+    pub port: Option<u16>,
+    pub protocol: Protocol,
+    // Key printed on the adaptor, used to register the uuid with common/register_terminal
+    pub key: Option<String>,
+    // Estimates power draw for units that don't report it
+    pub power_model: Option<PowerModel>,
+}
+
+// Strips separators and upper-cases a MAC address so "60:f1:89:b4:b2:d0" matches "60F189B4B2D0"
+pub fn normalize_mac(mac: &str) -> String {
+    mac.chars()
+        .filter(|c| c.is_ascii_hexdigit())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn power_model_watts() {
+        let linear = PowerModel {
+            name: "linear".to_string(),
+            rated_watts: 1000.0,
+            idle_watts: 10.0,
+            curve: vec![],
+        };
+
+        let curve = PowerModel {
+            name: "curve".to_string(),
+            rated_watts: 1500.0,
+            idle_watts: 20.0,
+            // Out of order on purpose, points are sorted by frequency
+            curve: vec![[80.0, 1200.0], [20.0, 300.0], [120.0, 1800.0]],
+        };
+
+        let cases = [
+            (&linear, 0.0, 10.0),
+            (&linear, -5.0, 10.0),
+            (&linear, 50.0, 505.0),
+            (&linear, 100.0, 1000.0),
+            (&linear, 150.0, 1000.0),
+            (&curve, 0.0, 20.0),
+            (&curve, 10.0, 160.0),
+            (&curve, 20.0, 300.0),
+            (&curve, 50.0, 750.0),
+            (&curve, 80.0, 1200.0),
+            // Interpolates to 1500 at 100 and is capped at rated_watts above it
+            (&curve, 100.0, 1500.0),
+            (&curve, 110.0, 1500.0),
+            (&curve, 200.0, 1500.0),
+        ];
+
+        for (model, frequency, expected) in cases {
+            let watts = model.watts(frequency);
+
+            assert!(
+                (watts - expected).abs() < 1e-9,
+                "{} at {}: {} != {}",
+                model.name,
+                frequency,
+                watts,
+                expected
+            );
+        }
     }
 }
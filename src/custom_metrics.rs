@@ -0,0 +1,245 @@
+use crate::configuration::Decoding;
+use crate::configuration::MetricKind;
+use crate::configuration::MetricMapping;
+use crate::daikin_adaptor::decode;
+use crate::daikin_adaptor::percent_decode;
+use crate::daikin_adaptor::Info;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+
+use log::error;
+
+use prometheus::core::Collector;
+use prometheus::core::Desc;
+use prometheus::proto::LabelPair;
+use prometheus::proto::Metric;
+use prometheus::proto::MetricFamily;
+use prometheus::proto::MetricType;
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+// Value of a mapping for the device and name labels of a unit
+type Values = BTreeMap<(usize, String, String), f64>;
+
+// Metrics declared by `[[metric]]` entries.  The collector exports the value of each mapping last
+// recorded for each unit.
+#[derive(Clone)]
+pub struct CustomMetrics {
+    mappings: Arc<Vec<MetricMapping>>,
+    descs: Vec<Desc>,
+    values: Arc<Mutex<Values>>,
+}
+
+impl CustomMetrics {
+    // Registers the collector for `mappings` with the default registry.  Returns None when no
+    // metrics are configured.
+    pub fn register(mappings: &[MetricMapping]) -> Result<Option<Self>> {
+        if mappings.is_empty() {
+            return Ok(None);
+        }
+
+        let mut descs = vec![];
+
+        for mapping in mappings {
+            if descs.iter().any(|desc: &Desc| desc.fq_name == mapping.name) {
+                return Err(anyhow!("Metric {} is configured twice", mapping.name));
+            }
+
+            let help = match &mapping.help {
+                Some(help) => help.clone(),
+                None => format!("{} from {}", mapping.key, mapping.endpoint),
+            };
+
+            let desc = Desc::new(
+                mapping.name.clone(),
+                help,
+                vec!["device".to_string(), "name".to_string()],
+                HashMap::new(),
+            )
+            .with_context(|| format!("Invalid metric {}", mapping.name))?;
+
+            descs.push(desc);
+        }
+
+        let metrics = CustomMetrics {
+            mappings: Arc::new(mappings.to_vec()),
+            descs,
+            values: Arc::new(Mutex::new(BTreeMap::new())),
+        };
+
+        prometheus::register(Box::new(metrics.clone()))
+            .context("Unable to register custom metrics")?;
+
+        Ok(Some(metrics))
+    }
+
+    // Records the values mapped from the response `info` from the adaptor endpoint `path`.  A
+    // mapped key missing from the response removes its series.
+    pub fn record(&self, path: &str, device: [&str; 2], info: &Info) {
+        let mut values = self.values.lock().unwrap();
+
+        for (index, mapping) in self.mappings.iter().enumerate() {
+            if mapping.endpoint != path {
+                continue;
+            }
+
+            let series = (index, device[0].to_string(), device[1].to_string());
+
+            let value = match info.get(&mapping.key) {
+                Some(v) => v,
+                None => {
+                    values.remove(&series);
+                    continue;
+                }
+            };
+
+            match mapped_value(mapping, value) {
+                Some(v) => {
+                    values.insert(series, v);
+                }
+                None => error!(
+                    "Invalid value {} for metric {} {}",
+                    value, device[0], mapping.name
+                ),
+            }
+        }
+    }
+
+    // Removes every series for a device
+    pub fn remove(&self, device: [&str; 2]) {
+        self.values
+            .lock()
+            .unwrap()
+            .retain(|(_, id, name), _| !(id == device[0] && name == device[1]));
+    }
+}
+
+impl Collector for CustomMetrics {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let values = self.values.lock().unwrap();
+
+        let mut families: Vec<MetricFamily> = self
+            .mappings
+            .iter()
+            .zip(&self.descs)
+            .map(|(mapping, desc)| {
+                let mut family = MetricFamily::default();
+                family.set_name(desc.fq_name.clone());
+                family.set_help(desc.help.clone());
+                family.set_field_type(match mapping.metric_type {
+                    MetricKind::Gauge => MetricType::GAUGE,
+                    MetricKind::Counter => MetricType::COUNTER,
+                });
+
+                family
+            })
+            .collect();
+
+        for ((index, id, name), value) in values.iter() {
+            let family = &mut families[*index];
+
+            let mut metric = Metric::default();
+
+            for (label, label_value) in [("device", id), ("name", name)] {
+                let mut pair = LabelPair::default();
+                pair.set_name(label.to_string());
+                pair.set_value(label_value.clone());
+                metric.mut_label().push(pair);
+            }
+
+            match family.get_field_type() {
+                MetricType::COUNTER => metric.mut_counter().set_value(*value),
+                _ => metric.mut_gauge().set_value(*value),
+            }
+
+            family.mut_metric().push(metric);
+        }
+
+        families
+            .into_iter()
+            .filter(|family| !family.get_metric().is_empty())
+            .collect()
+    }
+}
+
+// Decodes `value`, looks it up in the enum map when there is one and scales it
+fn mapped_value(mapping: &MetricMapping, value: &str) -> Option<f64> {
+    let decoded = match mapping.decode {
+        Decoding::None => value.to_string(),
+        Decoding::Percent => percent_decode(value),
+        Decoding::HexAscii => decode(value)?,
+    };
+
+    let number = if mapping.values.is_empty() {
+        decoded.trim().parse::<f64>().ok()?
+    } else {
+        *mapping.values.get(&decoded)?
+    };
+
+    Some(number * mapping.scale.unwrap_or(1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(options: &str) -> MetricMapping {
+        let source = format!(
+            r#"
+            endpoint = "aircon/get_sensor_info"
+            key = "cmpfreq"
+            name = "daikin_test"
+            {}
+            "#,
+            options
+        );
+
+        toml::from_str(&source).unwrap()
+    }
+
+    #[test]
+    fn mapped_values() {
+        let cases = [
+            ("", "38", Some(38.0)),
+            ("", " 38 ", Some(38.0)),
+            ("", "-", None),
+            ("scale = 0.1", "215", Some(21.5)),
+            (r#"decode = "hex-ascii""#, "323135", Some(215.0)),
+            (r#"decode = "hex-ascii""#, "3231x", None),
+            (r#"decode = "percent""#, "%32%31", Some(21.0)),
+            ("values = { run = 1, ota = 0 }", "ota", Some(0.0)),
+            ("values = { run = 1, ota = 0 }", "stop", None),
+            (
+                r#"values = { run = 1, ota = 0 }
+                   scale = 2.0"#,
+                "run",
+                Some(2.0),
+            ),
+            (
+                r#"decode = "hex-ascii"
+                   values = { A = 1 }"#,
+                "41",
+                Some(1.0),
+            ),
+        ];
+
+        for (options, value, expected) in cases {
+            assert_eq!(
+                expected,
+                mapped_value(&mapping(options), value),
+                "{} {}",
+                options,
+                value
+            );
+        }
+    }
+}
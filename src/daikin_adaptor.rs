@@ -1,14 +1,41 @@
+use crate::configuration::normalize_mac;
+use crate::configuration::Configuration;
+use crate::configuration::DeviceIdentity;
+use crate::configuration::EnumMetrics;
+use crate::configuration::HostSettings;
+use crate::configuration::Protocol;
+use crate::custom_metrics::CustomMetrics;
+use crate::daikin_discover::DiscoverHandle;
+use crate::daikin_hvac::HvacAction;
+use crate::daikin_hvac::Reading;
+use crate::daikin_schedule::ActiveSchedule;
+use crate::daikin_schedule::Schedule;
+
+use anyhow::anyhow;
+use anyhow::Result;
+
+use chrono::Local;
+use chrono::NaiveDateTime;
+use chrono::TimeZone;
+use chrono::Utc;
+
 use lazy_static::lazy_static;
 
 use log::debug;
 use log::error;
+use log::info;
 use log::trace;
+use log::warn;
 
 use prometheus::core::Collector;
+use prometheus::core::MetricVec;
+use prometheus::core::MetricVecBuilder;
+use prometheus::register_counter_vec;
 use prometheus::register_gauge_vec;
 use prometheus::register_histogram_vec;
 use prometheus::register_int_counter_vec;
 use prometheus::register_int_gauge_vec;
+use prometheus::CounterVec;
 use prometheus::GaugeVec;
 use prometheus::HistogramVec;
 use prometheus::IntCounterVec;
@@ -17,39 +44,50 @@ use prometheus::IntGaugeVec;
 use reqwest::Client;
 
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::RwLock;
 use std::time::Duration;
+use std::time::Instant;
 
 use tokio::time::interval;
 use tokio::time::MissedTickBehavior;
 
-type Info = HashMap<String, String>;
+// A detected adaptor type is kept until the unit has been unreachable this long
+const ADAPTOR_TYPE_EXPIRY: Duration = Duration::from_secs(300);
+
+// Schedules rarely change so the active schedule body is only read this often
+const SCHEDULE_REFRESH: Duration = Duration::from_secs(600);
+
+// Keys never exported as raw values.  basic_info carries the Daikin cloud account id and password
+// and the adaptor password.
+const RAW_SECRET_KEYS: [&str; 4] = ["ret", "id", "pw", "lpw"];
+
+pub type Info = HashMap<String, String>;
 type DaikinResponse = Result<Info, reqwest::Error>;
 
 macro_rules! set_metric {
-    ( $metric:ident, $value:ident, $parse:ty, $device_name:ident) => {
+    ( $metric:ident, $value:ident, $parse:ty, $device:ident) => {
         if let Ok(v) = $value.to_string().parse::<$parse>() {
-            $metric.with_label_values(&[&$device_name]).set(v);
+            $metric.with_label_values(&$device).set(v);
         } else {
             let desc = $metric.desc()[0];
             error!(
                 "Invalid value {} for metric {} {} ({})",
-                $value, $device_name, desc.fq_name, desc.help
+                $value, $device[0], desc.fq_name, desc.help
             );
         }
     };
 }
 
 macro_rules! set_metric_tenth {
-    ( $metric:ident, $value:ident, $parse:ty, $device_name:ident) => {
+    ( $metric:ident, $value:ident, $parse:ty, $device:ident) => {
         if let Ok(v) = $value.parse::<$parse>() {
-            $metric
-                .with_label_values(&[&$device_name])
-                .set(v / 10 as $parse);
+            $metric.with_label_values(&$device).set(v / 10 as $parse);
         } else {
             let desc = $metric.desc()[0];
             error!(
                 "Invalid value {} for metric {} {} ({})",
-                $value, $device_name, desc.fq_name, desc.help
+                $value, $device[0], desc.fq_name, desc.help
             );
         }
     };
@@ -75,147 +113,655 @@ lazy_static! {
     )
     .unwrap();
     static ref POWER_ON: IntGaugeVec =
-        register_int_gauge_vec!("daikin_power_on", "Daikin unit is on", &["device"]).unwrap();
+        register_int_gauge_vec!("daikin_power_on", "Daikin unit is on", &["device", "name"])
+            .unwrap();
     static ref MODE: IntGaugeVec = register_int_gauge_vec!(
         "daikin_mode",
         "Daikin mode (0, 1, 7 auto, 2 dehumidify, 3 cool, 4 heat, 6 fan)",
-        &["device"]
+        &["device", "name"]
+    )
+    .unwrap();
+    static ref MODE_STATE: IntGaugeVec = register_int_gauge_vec!(
+        "daikin_mode_state",
+        "Daikin mode is auto, dry, cool, heat or fan",
+        &["device", "name", "mode"]
     )
     .unwrap();
     static ref SET_HUMID: IntGaugeVec = register_int_gauge_vec!(
         "daikin_set_humidity_relative",
         "Humidity set-point",
-        &["device"]
+        &["device", "name"]
     )
     .unwrap();
     static ref SET_TEMP: GaugeVec = register_gauge_vec!(
         "daikin_set_temperature_degrees",
         "Temperature set-point",
-        &["device"]
+        &["device", "name"]
     )
     .unwrap();
     static ref FAN_RATE: IntGaugeVec = register_int_gauge_vec!(
         "daikin_fan_rate",
         "Daikin fan rate (1 auto, 2 quiet, 3–7 level 1–5)",
-        &["device"]
+        &["device", "name"]
+    )
+    .unwrap();
+    static ref FAN_RATE_STATE: IntGaugeVec = register_int_gauge_vec!(
+        "daikin_fan_rate_state",
+        "Daikin fan rate is auto, quiet or level_1–level_5",
+        &["device", "name", "fan_rate"]
     )
     .unwrap();
     static ref FAN_DIR: IntGaugeVec = register_int_gauge_vec!(
         "daikin_fan_direction",
         "Daikin fan direction (0 stopped, 1 vertical, 2 horizontal, 3 both)",
-        &["device"]
+        &["device", "name"]
     )
     .unwrap();
     static ref UNIT_TEMP: GaugeVec = register_gauge_vec!(
         "daikin_unit_temperature_degrees",
         "Unit temperature",
-        &["device"]
+        &["device", "name"]
     )
     .unwrap();
     static ref OUTDOOR_TEMP: GaugeVec = register_gauge_vec!(
         "daikin_outdoor_temperature_degrees",
         "Outdoor temperature",
-        &["device"]
+        &["device", "name"]
     )
     .unwrap();
     static ref COMPRESSOR_DEMAND: IntGaugeVec = register_int_gauge_vec!(
         "daikin_compressor_demand_percent",
         "Compressor demand (0–100)",
-        &["device"]
+        &["device", "name"]
+    )
+    .unwrap();
+    static ref DAILY_RUNTIME: IntGaugeVec = register_int_gauge_vec!(
+        "daikin_daily_runtime_minutes",
+        "Daily runtime",
+        &["device", "name"]
     )
     .unwrap();
-    static ref DAILY_RUNTIME: IntGaugeVec =
-        register_int_gauge_vec!("daikin_daily_runtime_minutes", "Daily runtime", &["device"])
-            .unwrap();
     static ref MONITOR_FAN_SPEED: IntGaugeVec = register_int_gauge_vec!(
         "daikin_monitor_fan_speed_percent",
         "Unit fan speed (0–100)",
-        &["device"]
+        &["device", "name"]
     )
     .unwrap();
-    static ref MONITOR_RAWRTMP: IntGaugeVec = register_int_gauge_vec!(
+    static ref MONITOR_RAWRTMP: GaugeVec = register_gauge_vec!(
         "daikin_monitor_rawr_temperature_degrees",
-        "Room air temperature",
-        &["device"]
+        "Room air temperature measured by the unit before compensation",
+        &["device", "name"]
     )
     .unwrap();
-    static ref MONITOR_TRTMP: IntGaugeVec = register_int_gauge_vec!(
+    static ref MONITOR_TRTMP: GaugeVec = register_gauge_vec!(
         "daikin_monitor_tr_temperature_degrees",
-        "tr tempurature",
-        &["device"]
+        "Compensated room temperature, reported as the unit temperature",
+        &["device", "name"]
+    )
+    .unwrap();
+    static ref MONITOR_FANGL: IntGaugeVec = register_int_gauge_vec!(
+        "daikin_monitor_fangl",
+        "Louvre (fan angle) position",
+        &["device", "name"]
     )
     .unwrap();
-    static ref MONITOR_FANGL: IntGaugeVec =
-        register_int_gauge_vec!("daikin_monitor_fangl", "fangl", &["device"]).unwrap();
-    static ref MONITOR_HETMP: IntGaugeVec = register_int_gauge_vec!(
+    static ref MONITOR_HETMP: GaugeVec = register_gauge_vec!(
         "daikin_monitor_heat_exchanger_temperature_degrees",
-        "Heat exchanger",
-        &["device"]
+        "Indoor heat exchanger temperature",
+        &["device", "name"]
+    )
+    .unwrap();
+    static ref MONITOR_POWER_ON: IntGaugeVec = register_int_gauge_vec!(
+        "daikin_monitor_power_on",
+        "Daikin unit is on according to the monitor data",
+        &["device", "name"]
+    )
+    .unwrap();
+    static ref MONITOR_MODE: IntGaugeVec = register_int_gauge_vec!(
+        "daikin_monitor_mode",
+        "Daikin mode according to the monitor data, numbered like daikin_mode",
+        &["device", "name"]
+    )
+    .unwrap();
+    static ref MONITOR_RAW: GaugeVec = register_gauge_vec!(
+        "daikin_monitor_raw",
+        "Monitor data fields the exporter doesn't know the meaning of",
+        &["device", "name", "key"]
     )
     .unwrap();
     static ref MONITOR_RESETS: IntGaugeVec = register_int_gauge_vec!(
         "daikin_monitor_reset_count",
         "Wifi adatptor resets",
-        &["device"]
+        &["device", "name"]
     )
     .unwrap();
     static ref MONITOR_ROUTER_DISCONNECTS: IntGaugeVec = register_int_gauge_vec!(
         "daikin_monitor_router_disconnect_count",
         "Router disconnections",
-        &["device"]
+        &["device", "name"]
     )
     .unwrap();
     static ref MONITOR_POLLING_ERRORS: IntGaugeVec = register_int_gauge_vec!(
         "daikin_monitor_polling_error_count",
         "Polling errors",
-        &["device"]
+        &["device", "name"]
+    )
+    .unwrap();
+    static ref ZONE_ON: IntGaugeVec = register_int_gauge_vec!(
+        "daikin_zone_on",
+        "Daikin zone is open",
+        &["device", "name", "zone"]
+    )
+    .unwrap();
+    static ref CLOCK_SKEW: GaugeVec = register_gauge_vec!(
+        "daikin_clock_skew_seconds",
+        "Difference between the adaptor clock and the exporter clock",
+        &["device", "name"]
+    )
+    .unwrap();
+    static ref FIRMWARE: IntGaugeVec = register_int_gauge_vec!(
+        "daikin_firmware_info",
+        "Daikin adaptor firmware version and checksum",
+        &["device", "name", "version", "checksum"]
+    )
+    .unwrap();
+    static ref FIRMWARE_KNOWN: IntGaugeVec = register_int_gauge_vec!(
+        "daikin_firmware_known_good",
+        "Daikin adaptor firmware is on the known good list",
+        &["device", "name"]
+    )
+    .unwrap();
+    static ref SCHEDULE_ENABLED: IntGaugeVec = register_int_gauge_vec!(
+        "daikin_schedule_enabled",
+        "Daikin weekly schedule is enabled",
+        &["device", "name"]
+    )
+    .unwrap();
+    static ref SCHEDULE_NEXT_TIME: GaugeVec = register_gauge_vec!(
+        "daikin_schedule_next_action_timestamp_seconds",
+        "Time of the next scheduled action since unix epoch in seconds",
+        &["device", "name"]
+    )
+    .unwrap();
+    static ref SCHEDULE_NEXT_POWER_ON: IntGaugeVec = register_int_gauge_vec!(
+        "daikin_schedule_next_power_on",
+        "Daikin unit is on after the next scheduled action",
+        &["device", "name"]
+    )
+    .unwrap();
+    static ref SCHEDULE_NEXT_MODE: IntGaugeVec = register_int_gauge_vec!(
+        "daikin_schedule_next_mode",
+        "Daikin mode after the next scheduled action",
+        &["device", "name"]
+    )
+    .unwrap();
+    static ref HVAC_ACTION: IntGaugeVec = register_int_gauge_vec!(
+        "daikin_hvac_action",
+        "Daikin unit is off, idle, heating, cooling, drying or only running the fan",
+        &["device", "name", "action"]
+    )
+    .unwrap();
+    static ref TEMP_DELTA: GaugeVec = register_gauge_vec!(
+        "daikin_temperature_delta_degrees",
+        "Temperature set-point minus unit temperature",
+        &["device", "name"]
+    )
+    .unwrap();
+    static ref STATE_SECONDS: CounterVec = register_counter_vec!(
+        "daikin_state_seconds_total",
+        "Time the Daikin unit spent in each hvac action",
+        &["device", "name", "state"]
+    )
+    .unwrap();
+    static ref ESTIMATED_POWER: GaugeVec = register_gauge_vec!(
+        "daikin_estimated_power_watts",
+        "Power draw estimated from compressor frequency by the configured power model",
+        &["device", "name"]
+    )
+    .unwrap();
+    static ref ESTIMATED_ENERGY: CounterVec = register_counter_vec!(
+        "daikin_estimated_energy_joules_total",
+        "Energy used estimated from compressor frequency by the configured power model",
+        &["device", "name"]
+    )
+    .unwrap();
+    static ref RAW_VALUE: GaugeVec = register_gauge_vec!(
+        "daikin_raw_value",
+        "Numeric value of a key from a Daikin adaptor endpoint",
+        &["device", "name", "endpoint", "key"]
+    )
+    .unwrap();
+    static ref RAW_INFO: IntGaugeVec = register_int_gauge_vec!(
+        "daikin_raw_info",
+        "Non-numeric value of a key from a Daikin adaptor endpoint",
+        &["device", "name", "endpoint", "key", "value"]
+    )
+    .unwrap();
+    static ref DEVICE_GAUGES: Vec<&'static GaugeVec> = vec![
+        &SET_TEMP,
+        &UNIT_TEMP,
+        &OUTDOOR_TEMP,
+        &SCHEDULE_NEXT_TIME,
+        &CLOCK_SKEW,
+        &TEMP_DELTA,
+        &ESTIMATED_POWER,
+        &MONITOR_RAWRTMP,
+        &MONITOR_TRTMP,
+        &MONITOR_HETMP
+    ];
+    static ref DEVICE_INT_GAUGES: Vec<&'static IntGaugeVec> = vec![
+        &POWER_ON,
+        &MODE,
+        &SET_HUMID,
+        &FAN_RATE,
+        &FAN_DIR,
+        &COMPRESSOR_DEMAND,
+        &DAILY_RUNTIME,
+        &MONITOR_FAN_SPEED,
+        &MONITOR_FANGL,
+        &MONITOR_POWER_ON,
+        &MONITOR_MODE,
+        &MONITOR_RESETS,
+        &MONITOR_ROUTER_DISCONNECTS,
+        &MONITOR_POLLING_ERRORS,
+        &FIRMWARE_KNOWN,
+        &SCHEDULE_ENABLED,
+        &SCHEDULE_NEXT_POWER_ON,
+        &SCHEDULE_NEXT_MODE,
+    ];
+}
+
+// HTTP client for talking to adaptors.  Timeouts are set per request as they may be overridden
+// per host.
+pub fn client() -> Client {
+    Client::builder()
+        .http1_only()
+        .build()
+        .expect("Could not build client")
+}
+
+lazy_static! {
+    // Client for adaptors configured for HTTPS, which use a self-signed certificate.  It is kept
+    // apart from the shared client so certificates are checked for all other traffic.
+    static ref HTTPS_CLIENT: Client = Client::builder()
+        .http1_only()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .expect("Could not build HTTPS client");
+}
+
+// Registers the info metric carrying the extra static labels from `[[host]]` entries.  Returns
+// None when no extra labels are configured.
+pub fn register_labels(label_names: &[String]) -> Option<GaugeVec> {
+    if label_names.is_empty() {
+        return None;
+    }
+
+    let mut labels = vec!["device"];
+    labels.extend(label_names.iter().map(|name| name.as_str()));
+
+    let metric = register_gauge_vec!(
+        "daikin_device_labels",
+        "Extra labels configured for the Daikin unit",
+        &labels
     )
     .unwrap();
+
+    Some(metric)
+}
+
+// Removes every series for a device from the per-device metrics
+fn remove_device_metrics(device: &[&str]) {
+    for metric in DEVICE_GAUGES.iter() {
+        let _ = metric.remove_label_values(device);
+    }
+
+    for metric in DEVICE_INT_GAUGES.iter() {
+        let _ = metric.remove_label_values(device);
+    }
+
+    remove_all_series(&ZONE_ON, device);
+    let _ = ESTIMATED_ENERGY.remove_label_values(device);
+
+    remove_all_series(&FIRMWARE, device);
+    remove_all_series(&MODE_STATE, device);
+    remove_all_series(&FAN_RATE_STATE, device);
+    remove_all_series(&MONITOR_RAW, device);
+    remove_all_series(&RAW_VALUE, device);
+    remove_all_series(&RAW_INFO, device);
+    remove_all_series(&HVAC_ACTION, device);
+    remove_all_series(&STATE_SECONDS, device);
+}
+
+// Removes every series of `metric` for `device` whatever its other labels are
+fn remove_all_series<T: MetricVecBuilder>(metric: &MetricVec<T>, device: &[&str]) {
+    remove_series(metric, |labels| {
+        labels.get("device") == Some(&device[0]) && labels.get("name") == Some(&device[1])
+    });
+}
+
+// Removes every series of `metric` whose labels match `filter`
+fn remove_series<T, F>(metric: &MetricVec<T>, filter: F)
+where
+    T: MetricVecBuilder,
+    F: Fn(&HashMap<&str, &str>) -> bool,
+{
+    for family in metric.collect() {
+        for series in family.get_metric() {
+            let labels: HashMap<&str, &str> = series
+                .get_label()
+                .iter()
+                .map(|label| (label.get_name(), label.get_value()))
+                .collect();
+
+            if filter(&labels) {
+                let _ = metric.remove(&labels);
+            }
+        }
+    }
+}
+
+// Size of the firmware image the ComfortControl app asks the adaptor to checksum
+const FIRMWARE_SIZE: u32 = 487320;
+
+// A firmware build, identified by the basic_info version and the get_progsum checksum
+#[derive(Clone, PartialEq)]
+struct Firmware {
+    version: String,
+    checksum: Option<String>,
+}
+
+// The device and name labels applied to every per-device metric
+#[derive(Clone, PartialEq)]
+struct Device {
+    id: String,
+    name: String,
+}
+
+impl Device {
+    fn labels(&self) -> [&str; 2] {
+        [&self.id, &self.name]
+    }
+}
+
+// The HTTP API spoken by the adaptor, detected by which basic_info endpoint answers
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AdaptorType {
+    // BRP072A, BRP069B and similar wifi adaptors
+    Standard,
+    // AirBase (BRP15B61) duct adaptors, which serve the SkyFi API under /skyfi
+    AirBase,
+}
+
+impl AdaptorType {
+    fn basic_info_path(&self) -> &'static str {
+        match self {
+            AdaptorType::Standard => "common/basic_info",
+            AdaptorType::AirBase => "skyfi/common/basic_info",
+        }
+    }
+
+    fn default_endpoints(&self) -> &'static [&'static str] {
+        match self {
+            AdaptorType::Standard => &[
+                "aircon/get_control_info",
+                "aircon/get_sensor_info",
+                "aircon/get_week_power",
+                "aircon/get_monitordata",
+                "aircon/get_scdltimer_info",
+            ],
+            AdaptorType::AirBase => &[
+                "skyfi/aircon/get_control_info",
+                "skyfi/aircon/get_sensor_info",
+                "skyfi/aircon/get_zone_setting",
+            ],
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct DaikinAdaptor {
-    pub host: String,
-    interval: Duration,
+    // Shared with the watcher so a unit that moves to a new IP keeps its poller
+    host: Arc<RwLock<String>>,
+    mac: Arc<RwLock<Option<String>>>,
+    configuration: Arc<Configuration>,
+    settings: HostSettings,
+    labels: Option<GaugeVec>,
+    custom_metrics: Option<CustomMetrics>,
+    discover: DiscoverHandle,
+    up: bool,
+    // The uuid has been registered with an HTTPS adaptor
+    registered: bool,
+    // None until a basic_info endpoint answers
+    adaptor_type: Option<AdaptorType>,
+    // Type probed at the next poll while the adaptor type is unknown.  None probes both types.
+    next_probe: Option<AdaptorType>,
+    // basic_info reported en_setzone=1
+    zones: bool,
+    schedule: Option<ActiveSchedule>,
+    // Last time common/get_datetime was read
+    clock_checked: Option<Instant>,
+    // Skew when the clock was last set, to notice an adaptor that keeps its own time
+    clock_set_skew: Option<f64>,
+    // The clock won't be set again until it is back within the threshold
+    clock_sync_stopped: bool,
+    firmware: Option<Firmware>,
+    // aircon/get_model_info reported en_scdltmr=1.  None until it answers.
+    schedule_supported: Option<bool>,
+    // Action derived from the last poll and when it was seen, for daikin_state_seconds_total
+    action: Option<(HvacAction, Instant)>,
+    // Power estimated at the last poll and when, for daikin_estimated_energy_joules_total
+    power: Option<(f64, Instant)>,
+    // Last raw metrics value of each endpoint and key, so only its series is replaced on change
+    raw_values: HashMap<(String, String), String>,
 
-    device_name: Option<String>,
+    device: Arc<RwLock<Option<Device>>>,
+    last_success: Arc<RwLock<Instant>>,
 }
 
 impl DaikinAdaptor {
-    pub fn new(host: String, interval: Duration) -> Self {
-        let device_name = None;
+    pub fn new(
+        host: String,
+        mac: Option<String>,
+        configuration: Arc<Configuration>,
+        labels: Option<GaugeVec>,
+        custom_metrics: Option<CustomMetrics>,
+        discover: DiscoverHandle,
+    ) -> Self {
+        let settings = configuration.host_settings(&host, mac.as_deref());
+        let host = Arc::new(RwLock::new(host));
+        let mac = Arc::new(RwLock::new(mac));
+        let device = Arc::new(RwLock::new(None));
+        let last_success = Arc::new(RwLock::new(Instant::now()));
 
         DaikinAdaptor {
             host,
-            interval,
-            device_name,
+            mac,
+            configuration,
+            settings,
+            labels,
+            custom_metrics,
+            discover,
+            up: false,
+            registered: false,
+            adaptor_type: None,
+            next_probe: None,
+            zones: false,
+            schedule: None,
+            clock_checked: None,
+            clock_set_skew: None,
+            clock_sync_stopped: false,
+            firmware: None,
+            schedule_supported: None,
+            action: None,
+            power: None,
+            raw_values: HashMap::new(),
+            device,
+            last_success,
         }
     }
 
-    pub async fn read_loop(&mut self, client: Client) {
-        let mut interval = interval(self.interval);
-        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    pub fn host(&self) -> String {
+        self.host.read().unwrap().clone()
+    }
+
+    pub fn set_host(&self, host: &str) {
+        *self.host.write().unwrap() = host.to_string();
+    }
+
+    // MAC address of the unit, once known
+    pub fn mac(&self) -> Option<String> {
+        self.mac.read().unwrap().clone()
+    }
+
+    pub fn set_mac(&self, mac: &str) {
+        *self.mac.write().unwrap() = Some(mac.to_string());
+    }
 
+    fn device(&self) -> Option<Device> {
+        self.device.read().unwrap().clone()
+    }
+
+    // Value of the device label, once the unit has answered
+    pub fn device_id(&self) -> Option<String> {
+        self.device
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|device| device.id.clone())
+    }
+
+    // Last time the unit answered a poll, or when polling started if it never has
+    pub fn last_success(&self) -> Instant {
+        *self.last_success.read().unwrap()
+    }
+
+    // Removes the series for this unit once it is no longer polled
+    pub fn remove_metrics(&self) {
+        let device = match self.device() {
+            Some(d) => d,
+            None => return,
+        };
+
+        remove_device_metrics(&device.labels());
+
+        if let Some(custom_metrics) = &self.custom_metrics {
+            custom_metrics.remove(device.labels());
+        }
+
+        if let Some(labels) = &self.labels {
+            let _ = labels.remove_label_values(&self.label_values(&device.id));
+        }
+    }
+
+    pub async fn read_loop(&mut self, client: Client) {
         loop {
-            interval.tick().await;
+            let period = self.settings.refresh_interval;
+            let mut interval = interval(period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
-            self.read_device(&client).await;
+            // Settings may change once the MAC address of a discovered unit is known
+            while self.settings.refresh_interval == period {
+                interval.tick().await;
+
+                self.read_device(&client).await;
+            }
         }
     }
 
     async fn read_device(&mut self, client: &Client) {
-        if let Some(basic_info) = self.get_info(client, "common/basic_info").await {
-            let device_name = percent_decode(basic_info.get("name").unwrap());
+        let mut reading = Reading::default();
+
+        // The MAC of a configured host may have been learned from discovery since the last poll
+        self.settings = self
+            .configuration
+            .host_settings(&self.host(), self.mac().as_deref());
+
+        if self.needs_registration() {
+            self.registered = self.register(client).await;
+
+            if self.registered && self.adaptor_type.is_some() {
+                // The adaptor was reset, possibly with new firmware, so detect its type again
+                self.adaptor_type = None;
+                self.next_probe = None;
+            }
+        }
+
+        if let Some(basic_info) = self.get_basic_info(client).await {
+            let mac = basic_info.get("mac").map(|mac| normalize_mac(mac));
+
+            match (self.mac(), &mac) {
+                (Some(known), Some(mac)) if known != *mac => {
+                    // Another unit was given this unit's old IP and the watcher hasn't seen
+                    // where this unit moved to yet.
+                    warn!(
+                        "Daikin adaptor {} at {} answered as {}, skipping",
+                        known,
+                        self.host(),
+                        mac
+                    );
+                    return;
+                }
+                (None, Some(mac)) => *self.mac.write().unwrap() = Some(mac.clone()),
+                _ => (),
+            }
+
+            *self.last_success.write().unwrap() = Instant::now();
+
+            let mac = mac.as_deref();
+            self.settings = self.configuration.host_settings(&self.host(), mac);
+
+            let name = match &self.settings.name {
+                Some(name) => name.clone(),
+                None => basic_info
+                    .get("name")
+                    .map(|name| percent_decode(name))
+                    .unwrap_or_default(),
+            };
+
+            let id = match (self.configuration.device_identity(), mac) {
+                (DeviceIdentity::Mac, Some(mac)) => mac.to_string(),
+                _ => name.clone(),
+            };
+
+            self.set_device(Device { id, name });
 
-            self.device_name = Some(device_name.clone());
+            self.zones = basic_info.get("en_setzone").map(|e| e.as_str()) == Some("1");
 
-            if let Some(power_on) = basic_info.get("pow") {
-                set_metric!(POWER_ON, power_on, i64, device_name);
+            let current = self.device().unwrap();
+            let path = self.adaptor_type.unwrap().basic_info_path();
+
+            record_response(&self.configuration, path, current.labels(), &basic_info);
+            self.record_raw(path, current.labels(), &basic_info);
+            reading.update(path, &basic_info);
+
+            if let Some(custom_metrics) = &self.custom_metrics {
+                custom_metrics.record(path, current.labels(), &basic_info);
+            }
+
+            if let Some(version) = basic_info.get("ver") {
+                self.read_firmware(client, current.labels(), version).await;
             }
+
+            if self.schedule_supported.is_none() && self.adaptor_type == Some(AdaptorType::Standard)
+            {
+                self.read_model_info(client, current.labels()).await;
+            }
+
+            self.up = true;
+        } else if self.up {
+            // The unit may have been given a new IP address
+            info!("Daikin adaptor {} stopped responding", self.host());
+            self.up = false;
+            // A reset adaptor forgets registered terminals
+            self.registered = false;
+            self.discover.rescan();
         }
 
-        let device_name = match &self.device_name {
-            Some(name) => name,
+        let current = self.device();
+
+        let device = match &current {
+            Some(device) => device.labels(),
             None => {
                 // We haven't retrieved the device name yet so we won't be able to assign the
                 // device label to any of the metrics we will collect below.
@@ -223,102 +769,699 @@ impl DaikinAdaptor {
             }
         };
 
-        if let Some(control_info) = self.get_info(client, "aircon/get_control_info").await {
-            if let Some(set_temp) = control_info.get("stemp") {
-                set_metric!(SET_TEMP, set_temp, f64, device_name);
+        for path in self.endpoints() {
+            if let Some(info) = self.get_info(client, &path).await {
+                record_response(&self.configuration, &path, device, &info);
+                self.record_raw(&path, device, &info);
+
+                if let Some(custom_metrics) = &self.custom_metrics {
+                    custom_metrics.record(&path, device, &info);
+                }
+
+                if path == "aircon/get_scdltimer_info" {
+                    self.read_schedule(client, device, &info).await;
+                }
+
+                reading.update(&path, &info);
             }
+        }
+
+        self.record_action(device, &reading);
+        self.record_power(device, &reading);
+
+        let clock_due = match self.clock_checked {
+            Some(checked) => checked.elapsed() >= self.configuration.clock_check_interval(),
+            None => true,
+        };
 
-            if let Some(set_humid) = control_info.get("shum") {
-                set_metric!(SET_HUMID, set_humid, i64, device_name);
+        if clock_due && self.adaptor_type == Some(AdaptorType::Standard) {
+            self.clock_checked = Some(Instant::now());
+            self.read_clock(client, device).await;
+        }
+    }
+
+    // Records what the unit is doing and adds the time since the last poll to the previous action.
+    // Time while the unit isn't answering or the action is unknown isn't counted.
+    fn record_action(&mut self, device: [&str; 2], reading: &Reading) {
+        match reading.temperature_delta() {
+            Some(delta) => TEMP_DELTA.with_label_values(&device).set(delta),
+            None => {
+                let _ = TEMP_DELTA.remove_label_values(&device);
             }
+        }
 
-            if let Some(mode) = control_info.get("mode") {
-                set_metric!(MODE, mode, i64, device_name);
+        let action = match reading.action() {
+            Some(action) if self.up => action,
+            _ => {
+                self.action = None;
+                return;
             }
+        };
 
-            if let Some(fan_rate) = control_info.get("f_rate") {
-                let fan_rate = fan_rate.to_string();
-                let fan_rate = match fan_rate.as_str() {
-                   "A" => 1,
-                   "B" => 2,
-                   _ => fan_rate.parse::<i64>().unwrap()
-                };
+        for state in HvacAction::ALL {
+            let labels = [device[0], device[1], state.as_str()];
+
+            HVAC_ACTION
+                .with_label_values(&labels)
+                .set((state == action) as i64);
+
+            // Start every counter at zero so increase() sees the first interval in each state
+            let seconds = match self.action {
+                Some((previous, since)) if previous == state => since.elapsed().as_secs_f64(),
+                _ => 0.0,
+            };
+
+            STATE_SECONDS.with_label_values(&labels).inc_by(seconds);
+        }
+
+        self.action = Some((action, Instant::now()));
+    }
+
+    // Records the power draw estimated by the power model and adds the energy used since the last
+    // poll at the previous estimate.  Units that are off draw nothing.
+    fn record_power(&mut self, device: [&str; 2], reading: &Reading) {
+        let model = match &self.settings.power_model {
+            Some(model) => model,
+            None => return,
+        };
+
+        let watts = match (reading.power_on, reading.compressor_demand) {
+            _ if !self.up => None,
+            (Some(false), _) => Some(0.0),
+            (Some(true), Some(frequency)) => Some(model.watts(frequency)),
+            _ => None,
+        };
 
-                FAN_RATE.with_label_values(&[device_name]).set(fan_rate);
+        let watts = match watts {
+            Some(w) => w,
+            None => {
+                let _ = ESTIMATED_POWER.remove_label_values(&device);
+                self.power = None;
+                return;
             }
+        };
+
+        let joules = match self.power {
+            Some((previous, since)) => previous * since.elapsed().as_secs_f64(),
+            None => 0.0,
+        };
+
+        ESTIMATED_POWER.with_label_values(&device).set(watts);
+        ESTIMATED_ENERGY.with_label_values(&device).inc_by(joules);
+
+        self.power = Some((watts, Instant::now()));
+    }
+
+    // Records the firmware build, reading the checksum only when the version is new.  Firmware
+    // changes are logged.
+    async fn read_firmware(&mut self, client: &Client, device: [&str; 2], version: &str) {
+        let unchanged = match &self.firmware {
+            Some(firmware) => firmware.version == version,
+            None => false,
+        };
+
+        // Only standard adaptors report a checksum.  It is read again until get_progsum answers.
+        let standard = self.adaptor_type == Some(AdaptorType::Standard);
+        let checksum_missing = match &self.firmware {
+            Some(firmware) => unchanged && standard && firmware.checksum.is_none(),
+            None => false,
+        };
 
-            if let Some(fan_dir) = control_info.get("f_dir") {
-                set_metric!(FAN_DIR, fan_dir, i64, device_name);
+        if checksum_missing {
+            if let Some(checksum) = self.read_checksum(client, device).await {
+                remove_all_series(&FIRMWARE, &device);
+
+                self.firmware.as_mut().unwrap().checksum = Some(checksum);
             }
         }
 
-        if let Some(sensor_info) = self.get_info(client, "aircon/get_sensor_info").await {
-            let unit_temp = sensor_info.get("htemp").unwrap().to_string();
-            let outdoor_temp = sensor_info.get("otemp").unwrap().to_string();
-            let compressor_demand = sensor_info.get("cmpfreq").unwrap().to_string();
+        if !unchanged {
+            let checksum = if standard {
+                self.read_checksum(client, device).await
+            } else {
+                None
+            };
+
+            let firmware = Firmware {
+                version: version.to_string(),
+                checksum,
+            };
+
+            if let Some(old) = &self.firmware {
+                warn!(
+                    "Daikin adaptor {} firmware changed from {} ({}) to {} ({})",
+                    device[0],
+                    old.version,
+                    old.checksum.as_deref().unwrap_or("unknown"),
+                    firmware.version,
+                    firmware.checksum.as_deref().unwrap_or("unknown"),
+                );
+            }
+
+            remove_all_series(&FIRMWARE, &device);
 
-            set_metric!(UNIT_TEMP, unit_temp, f64, device_name);
-            set_metric!(OUTDOOR_TEMP, outdoor_temp, f64, device_name);
-            set_metric!(COMPRESSOR_DEMAND, compressor_demand, i64, device_name);
+            self.firmware = Some(firmware);
         }
 
-        if let Some(week_power) = self.get_info(client, "aircon/get_week_power").await {
-            let daily_runtime = week_power.get("today_runtime").unwrap().to_string();
+        let firmware = self.firmware.as_ref().unwrap();
+        let checksum = firmware.checksum.as_deref();
+
+        FIRMWARE
+            .with_label_values(&[
+                device[0],
+                device[1],
+                &firmware.version,
+                checksum.unwrap_or(""),
+            ])
+            .set(1);
+
+        let known_firmware = self.configuration.known_firmware();
 
-            set_metric!(DAILY_RUNTIME, daily_runtime, i64, device_name);
+        if known_firmware.is_empty() {
+            return;
         }
 
-        if let Some(monitor_data) = self.get_info(client, "aircon/get_monitordata").await {
-            //let monitor_tap = decode(monitor_data.get("tap").unwrap());
+        if standard && checksum.is_none() {
+            // Unknown until the checksum can be compared
+            let _ = FIRMWARE_KNOWN.remove_label_values(&device);
+            return;
+        }
+
+        let known = known_firmware
+            .iter()
+            .any(|known| known.matches(&firmware.version, checksum));
+
+        FIRMWARE_KNOWN.with_label_values(&device).set(known as i64);
+    }
+
+    // Reads whether the unit supports weekly schedules.  The model doesn't change so this is read
+    // until the adaptor answers once.
+    async fn read_model_info(&mut self, client: &Client, device: [&str; 2]) {
+        let model_info = match self.get_info(client, "aircon/get_model_info").await {
+            Some(m) => m,
+            None => return,
+        };
+
+        self.record_raw("aircon/get_model_info", device, &model_info);
 
-            // Probably duplicate from control info
-            //let monitor_mode = decode(monitor_data.get("mode").unwrap());
+        let supported = model_info.get("en_scdltmr").map(|e| e.as_str()) == Some("1");
 
-            // Probably duplicate from control info
-            //let monitor_pow = decode(monitor_data.get("pow").unwrap());
+        debug!(
+            "Daikin adaptor {} schedule support: {}",
+            self.host(),
+            supported
+        );
 
-            let monitor_fan_speed = decode(monitor_data.get("fan").unwrap());
-            let monitor_rawrtmp = decode(monitor_data.get("rawrtmp").unwrap());
-            let monitor_trtmp = decode(monitor_data.get("trtmp").unwrap());
-            let monitor_fangl = decode(monitor_data.get("fangl").unwrap());
-            let monitor_hetmp = decode(monitor_data.get("hetmp").unwrap());
-            let monitor_resets = monitor_data.get("ResetCount").unwrap().to_string();
-            let monitor_router_disconnects =
-                monitor_data.get("RouterDisconCnt").unwrap().to_string();
-            let monitor_polling_errors = monitor_data.get("PollingErrCnt").unwrap().to_string();
+        self.schedule_supported = Some(supported);
+    }
+
+    // Checksum of the running firmware from common/get_progsum, None if the adaptor didn't answer
+    async fn read_checksum(&mut self, client: &Client, device: [&str; 2]) -> Option<String> {
+        let query = format!("fwsize={}", FIRMWARE_SIZE);
+
+        let progsum = self
+            .get_info_query(client, "common/get_progsum", &query)
+            .await?;
+
+        self.record_raw("common/get_progsum", device, &progsum);
+
+        progsum.get("csum").cloned()
+    }
+
+    // Records the drift of the adaptor's clock and sets it when it has drifted too far.  The
+    // adaptor reports local time, which is assumed to be in the exporter's time zone.  A skew of
+    // whole hours, or one that setting the clock didn't change, is an adaptor in another time zone
+    // so the clock is left alone.
+    async fn read_clock(&mut self, client: &Client, device: [&str; 2]) {
+        let datetime = match self.get_info(client, "common/get_datetime").await {
+            Some(d) => d,
+            None => return,
+        };
 
-            set_metric!(MONITOR_FAN_SPEED, monitor_fan_speed, i64, device_name);
-            set_metric_tenth!(MONITOR_RAWRTMP, monitor_rawrtmp, i64, device_name);
-            set_metric_tenth!(MONITOR_TRTMP, monitor_trtmp, i64, device_name);
-            set_metric!(MONITOR_FANGL, monitor_fangl, i64, device_name);
-            set_metric_tenth!(MONITOR_HETMP, monitor_hetmp, i64, device_name);
-            set_metric!(MONITOR_RESETS, monitor_resets, i64, device_name);
-            set_metric!(
-                MONITOR_ROUTER_DISCONNECTS,
-                monitor_router_disconnects,
-                i64,
-                device_name
+        self.record_raw("common/get_datetime", device, &datetime);
+
+        let current = match datetime.get("cur") {
+            Some(c) => c,
+            None => return,
+        };
+
+        let adaptor_time = NaiveDateTime::parse_from_str(current, "%Y/%m/%d %H:%M:%S")
+            .ok()
+            .and_then(|time| Local.from_local_datetime(&time).earliest());
+
+        let adaptor_time = match adaptor_time {
+            Some(t) => t,
+            None => {
+                error!("Invalid time {} from {}", current, device[0]);
+                return;
+            }
+        };
+
+        let skew = (adaptor_time - Local::now()).num_milliseconds() as f64 / 1000.0;
+
+        CLOCK_SKEW.with_label_values(&device).set(skew);
+
+        let threshold = self.configuration.clock_sync_threshold().as_secs_f64();
+
+        if skew.abs() <= threshold {
+            self.clock_set_skew = None;
+            self.clock_sync_stopped = false;
+            return;
+        }
+
+        if !self.configuration.clock_sync() || self.clock_sync_stopped {
+            return;
+        }
+
+        let hours = (skew / 3600.0).round();
+
+        if hours != 0.0 && (skew - hours * 3600.0).abs() <= threshold {
+            warn!(
+                "Clock of {} is off by {} hours, not setting it as it is probably in another \
+                 time zone",
+                device[0], hours
             );
-            set_metric!(
-                MONITOR_POLLING_ERRORS,
-                monitor_polling_errors,
-                i64,
-                device_name
+            self.clock_sync_stopped = true;
+            return;
+        }
+
+        if let Some(previous) = self.clock_set_skew {
+            if (skew - previous).abs() <= threshold {
+                warn!(
+                    "Clock of {} is still off by {}s after setting it, not setting it again",
+                    device[0], skew
+                );
+                self.clock_sync_stopped = true;
+                return;
+            }
+        }
+
+        self.clock_set_skew = Some(skew);
+        self.sync_clock(client, device, skew).await;
+    }
+
+    // Sends the host's time to the adaptor in UTC like the ComfortControl app
+    async fn sync_clock(&self, client: &Client, device: [&str; 2], skew: f64) {
+        let now = Utc::now();
+
+        let query = format!(
+            "zone=GMT&date={}&time={}",
+            now.format("%Y/%m/%d"),
+            now.format("%H%%3A%M%%3A%S")
+        );
+
+        let response = self
+            .request(client, "common/notify_date_time", &query)
+            .await;
+
+        // Adaptors answer THROUGH when they set their clock from the Daikin cloud instead
+        match response
+            .as_ref()
+            .and_then(|r| r.get("ret"))
+            .map(|r| r.as_str())
+        {
+            Some("OK") => info!("Set clock of {} which was off by {}s", device[0], skew),
+            Some(ret) => warn!("Unable to set clock of {}: {}", device[0], ret),
+            None => (),
+        }
+    }
+
+    // Records the next action of the active schedule.  The schedule body is read again when
+    // another schedule becomes active or the copy is old.
+    async fn read_schedule(&mut self, client: &Client, device: [&str; 2], info: &Info) {
+        let enabled = info.get("en_scdltimer").map(|e| e.as_str()) == Some("1");
+
+        SCHEDULE_ENABLED
+            .with_label_values(&device)
+            .set(enabled as i64);
+
+        let number = match info.get("active_no") {
+            Some(number) if enabled => number.clone(),
+            _ => {
+                self.schedule = None;
+                record_schedule(device, None);
+                return;
+            }
+        };
+
+        let stale = match &self.schedule {
+            Some(active) => active.number != number || active.fetched.elapsed() > SCHEDULE_REFRESH,
+            None => true,
+        };
+
+        if stale {
+            let query = format!("target={}", number);
+
+            let body = match self
+                .get_info_query(client, "aircon/get_scdltimer_body", &query)
+                .await
+            {
+                Some(body) => body,
+                None => return,
+            };
+
+            self.record_raw("aircon/get_scdltimer_body", device, &body);
+
+            let schedule = Schedule::parse(info.get("f_detail").map(|d| d.as_str()), &body);
+
+            self.schedule = Some(ActiveSchedule {
+                number,
+                fetched: Instant::now(),
+                schedule,
+            });
+        }
+
+        record_schedule(device, self.schedule.as_ref().map(|a| &a.schedule));
+    }
+
+    // Exports a response when raw metrics are enabled
+    fn record_raw(&mut self, path: &str, device: [&str; 2], info: &Info) {
+        if self.configuration.raw_metrics() {
+            record_raw(&mut self.raw_values, path, device, info);
+        }
+    }
+
+    // Records the labels for this unit.  When the unit is renamed the series for the old labels are
+    // removed so they don't linger alongside the new ones.
+    fn set_device(&mut self, device: Device) {
+        if let Some(old) = &self.device() {
+            if *old == device {
+                return;
+            }
+
+            info!(
+                "Daikin adaptor {} renamed from {} ({}) to {} ({})",
+                self.host(),
+                old.name,
+                old.id,
+                device.name,
+                device.id
+            );
+
+            remove_device_metrics(&old.labels());
+            self.raw_values.clear();
+
+            if let Some(custom_metrics) = &self.custom_metrics {
+                custom_metrics.remove(old.labels());
+            }
+
+            if let Some(labels) = &self.labels {
+                let _ = labels.remove_label_values(&self.label_values(&old.id));
+            }
+        }
+
+        if let Some(labels) = &self.labels {
+            labels
+                .with_label_values(&self.label_values(&device.id))
+                .set(1.0);
+        }
+
+        *self.device.write().unwrap() = Some(device);
+    }
+
+    // Values for the daikin_device_labels metric
+    fn label_values<'a>(&'a self, id: &'a str) -> Vec<&'a str> {
+        let mut values = vec![id];
+
+        for name in self.configuration.host_label_names() {
+            let value = match self.settings.labels.get(&name) {
+                Some(v) => v.as_str(),
+                None => "",
+            };
+
+            values.push(value);
+        }
+
+        values
+    }
+
+    // Fetches basic_info, detecting the adaptor type from which endpoint answers when it isn't
+    // known yet.  The type is detected again once the unit has been unreachable for
+    // ADAPTOR_TYPE_EXPIRY.  The first detection probes both types, after that one type is probed
+    // per poll so an unreachable unit isn't sent both requests every poll.
+    async fn get_basic_info(&mut self, client: &Client) -> Option<Info> {
+        if let Some(adaptor_type) = self.adaptor_type {
+            let unreachable = self.last_success().elapsed();
+
+            if unreachable < ADAPTOR_TYPE_EXPIRY {
+                return self.get_info(client, adaptor_type.basic_info_path()).await;
+            }
+
+            debug!(
+                "Daikin adaptor {} unreachable for {:?}, detecting its type again",
+                self.host(),
+                unreachable
             );
+
+            self.adaptor_type = None;
+            self.next_probe = Some(adaptor_type);
+        }
+
+        let probes = match self.next_probe {
+            Some(adaptor_type) => vec![adaptor_type],
+            None => vec![AdaptorType::Standard, AdaptorType::AirBase],
+        };
+
+        for adaptor_type in probes {
+            self.adaptor_type = Some(adaptor_type);
+
+            if let Some(info) = self.get_info(client, adaptor_type.basic_info_path()).await {
+                debug!("Daikin adaptor {} is {:?}", self.host(), adaptor_type);
+                self.next_probe = None;
+                return Some(info);
+            }
+        }
+
+        self.adaptor_type = None;
+        self.next_probe = match self.next_probe {
+            Some(AdaptorType::Standard) => Some(AdaptorType::AirBase),
+            _ => Some(AdaptorType::Standard),
+        };
+
+        None
+    }
+
+    // Endpoints polled after basic_info
+    fn endpoints(&self) -> Vec<String> {
+        let adaptor_type = self.adaptor_type.unwrap_or(AdaptorType::Standard);
+
+        let mut endpoints: Vec<String> = match &self.settings.endpoints {
+            Some(endpoints) => endpoints.clone(),
+            None => adaptor_type
+                .default_endpoints()
+                .iter()
+                .map(|endpoint| endpoint.to_string())
+                .collect(),
+        };
+
+        if self.settings.endpoints.is_none() && adaptor_type == AdaptorType::Standard && self.zones
+        {
+            endpoints.push("aircon/get_zone_setting".to_string());
+        }
+
+        // Schedules are only polled on units that advertise them
+        if self.settings.endpoints.is_none() && self.schedule_supported != Some(true) {
+            endpoints.retain(|endpoint| endpoint != "aircon/get_scdltimer_info");
+        }
+
+        // Endpoints of `[[metric]]` entries for this adaptor type are always polled
+        for mapping in self.configuration.metrics() {
+            let airbase = mapping.endpoint.starts_with("skyfi/");
+
+            if airbase == (adaptor_type == AdaptorType::AirBase)
+                && mapping.endpoint != adaptor_type.basic_info_path()
+                && !endpoints.contains(&mapping.endpoint)
+            {
+                endpoints.push(mapping.endpoint.clone());
+            }
+        }
+
+        endpoints
+    }
+
+    // Connects to the adaptor for a one-off command, registering and detecting the adaptor type
+    pub async fn connect(&mut self, client: &Client) -> Result<()> {
+        if self.needs_registration() {
+            self.registered = self.register(client).await;
+        }
+
+        match self.get_basic_info(client).await {
+            Some(_) => Ok(()),
+            None => Err(anyhow!("Daikin adaptor {} did not respond", self.host())),
+        }
+    }
+
+    // Fetches `path` for a one-off command, failing unless the adaptor answers ret=OK
+    pub async fn fetch(&self, client: &Client, path: &str, query: &str) -> Result<Info> {
+        let response = self
+            .request(client, path, query)
+            .await
+            .ok_or_else(|| anyhow!("Request to {} {} failed", self.host(), path))?;
+
+        check_ret(&self.host(), &response)?;
+
+        Ok(response)
+    }
+
+    // Path of the aircon `endpoint` for this adaptor type
+    fn aircon_path(&self, endpoint: &str) -> String {
+        match self.adaptor_type {
+            Some(AdaptorType::AirBase) => format!("skyfi/aircon/{}", endpoint),
+            _ => format!("aircon/{}", endpoint),
+        }
+    }
+
+    // Opens or closes the zone named `zone`.  The adaptor sets every zone at once so the current
+    // settings are read and sent back with the one zone changed.
+    pub async fn set_zone(&self, client: &Client, zone: &str, on: bool) -> Result<()> {
+        let path = self.aircon_path("get_zone_setting");
+        let setting = self
+            .get_info(client, &path)
+            .await
+            .ok_or_else(|| anyhow!("Unable to read zones from {}", self.host()))?;
+
+        let (names, states) = match (setting.get("zone_name"), setting.get("zone_onoff")) {
+            (Some(names), Some(states)) => (names, percent_decode(states)),
+            _ => return Err(anyhow!("Daikin adaptor {} has no zones", self.host())),
+        };
+
+        let index = percent_decode(names)
+            .split(';')
+            .position(|name| name.trim() == zone)
+            .ok_or_else(|| anyhow!("Daikin adaptor {} has no zone {}", self.host(), zone))?;
+
+        let mut states: Vec<&str> = states.split(';').collect();
+
+        if index >= states.len() {
+            return Err(anyhow!(
+                "Daikin adaptor {} has no state for zone {}",
+                self.host(),
+                zone
+            ));
+        }
+
+        states[index] = if on { "1" } else { "0" };
+
+        let query = format!(
+            "zone_name={}&zone_onoff={}",
+            names,
+            percent_encode(&states.join(";"))
+        );
+
+        let path = self.aircon_path("set_zone_setting");
+        let response = self
+            .request(client, &path, &query)
+            .await
+            .ok_or_else(|| anyhow!("Unable to set zones on {}", self.host()))?;
+
+        check_ret(&self.host(), &response)
+    }
+
+    fn needs_registration(&self) -> bool {
+        self.settings.key.is_some() && !self.registered
+    }
+
+    // Registers the uuid with an adaptor using the key printed on the adaptor.  Returns
+    // true when the adaptor accepted the registration.
+    async fn register(&self, client: &Client) -> bool {
+        let key = self.settings.key.clone().unwrap();
+
+        if self.settings.uuid.is_none() {
+            error!(
+                "Daikin adaptor {} has a key but no uuid to register",
+                self.host()
+            );
+            return false;
+        }
+
+        let info = self
+            .request(client, "common/register_terminal", &format!("key={}", key))
+            .await;
+
+        match info.as_ref().and_then(|info| info.get("ret")) {
+            Some(ret) if ret == "OK" => {
+                info!("Registered with Daikin adaptor {}", self.host());
+                true
+            }
+            Some(ret) => {
+                warn!(
+                    "Daikin adaptor {} rejected registration: {}",
+                    self.host(),
+                    ret
+                );
+                false
+            }
+            None => false,
         }
     }
 
     async fn get_info(&self, client: &Client, path: &str) -> Option<Info> {
+        self.get_info_query(client, path, "").await
+    }
+
+    // Fetches `path` with the already encoded `query` for polling.  Adaptors answer unsupported
+    // endpoints and bad parameters with HTTP 200 and a ret other than OK, which is counted as an
+    // error.
+    async fn get_info_query(&self, client: &Client, path: &str, query: &str) -> Option<Info> {
+        let info = self.request(client, path, query).await?;
+
+        match info.get("ret").map(|r| r.as_str()) {
+            Some("OK") => Some(info),
+            ret => {
+                debug!(
+                    "Daikin adaptor {} answered {} with ret {}",
+                    self.host(),
+                    path,
+                    ret.unwrap_or("missing")
+                );
+                ERRORS.with_label_values(&[&self.host(), path, "ret"]).inc();
+                None
+            }
+        }
+    }
+
+    // Fetches `path` with the already encoded `query` whatever ret the adaptor answers.  The
+    // adaptor expects names percent-encoded byte by byte, which the query serializer won't
+    // produce.
+    async fn request(&self, client: &Client, path: &str, query: &str) -> Option<Info> {
+        let host = self.host();
         let path = path.to_string();
-        let url = format!("http://{}/{}", self.host, path);
+        let scheme = match self.settings.protocol {
+            Protocol::Http => "http",
+            Protocol::Https => "https",
+        };
+        let mut url = match self.settings.port {
+            Some(port) => format!("{}://{}:{}/{}", scheme, host, port, path),
+            None => format!("{}://{}/{}", scheme, host, path),
+        };
+
+        if !query.is_empty() {
+            url = format!("{}?{}", url, query);
+        }
 
         debug!("Fetching {}", url);
-        REQUESTS.with_label_values(&[&self.host, &path]).inc();
-        let timer = DURATIONS
-            .with_label_values(&[&self.host, &path])
-            .start_timer();
+        REQUESTS.with_label_values(&[&host, &path]).inc();
+        let timer = DURATIONS.with_label_values(&[&host, &path]).start_timer();
+
+        let client = match self.settings.protocol {
+            Protocol::Http => client,
+            Protocol::Https => &HTTPS_CLIENT,
+        };
 
-        let response = client.get(&url).send().await;
+        let mut request = client.get(&url).timeout(self.settings.refresh_timeout);
+
+        match (&self.settings.lpw, self.adaptor_type) {
+            (Some(lpw), _) => request = request.query(&[("lpw", lpw)]),
+            // AirBase adaptors reject requests without lpw even when there is no password
+            (None, Some(AdaptorType::AirBase)) => request = request.query(&[("lpw", "")]),
+            _ => (),
+        }
+
+        if let Some(uuid) = &self.settings.uuid {
+            request = request.header("X-Daikin-uuid", uuid);
+        }
+
+        let response = request.send().await;
 
         timer.observe_duration();
 
@@ -326,50 +1469,429 @@ impl DaikinAdaptor {
             Ok(r) => r,
             Err(e) => {
                 debug!("request error: {:?}", e);
-                ERRORS
-                    .with_label_values(&[&self.host, &path, "request"])
-                    .inc();
+                ERRORS.with_label_values(&[&host, &path, "request"]).inc();
                 return None;
             }
         };
 
+        if !response.status().is_success() {
+            debug!("request status: {}", response.status());
+            ERRORS.with_label_values(&[&host, &path, "status"]).inc();
+            return None;
+        }
+
         match result_hash(response).await {
             Ok(r) => Some(r),
             Err(e) => {
                 debug!("request body error: {:?}", e);
-                ERRORS.with_label_values(&[&self.host, &path, "body"]).inc();
+                ERRORS.with_label_values(&[&host, &path, "body"]).inc();
                 None
             }
         }
     }
 }
 
+// daikin_mode_state states and the daikin_mode values for each
+const MODE_STATES: [(&str, &[i64]); 5] = [
+    ("auto", &[0, 1, 7]),
+    ("dry", &[2]),
+    ("cool", &[3]),
+    ("heat", &[4]),
+    ("fan", &[6]),
+];
+
+// daikin_fan_rate_state states and the daikin_fan_rate value for each
+const FAN_RATE_STATES: [(&str, &[i64]); 7] = [
+    ("auto", &[1]),
+    ("quiet", &[2]),
+    ("level_1", &[3]),
+    ("level_2", &[4]),
+    ("level_3", &[5]),
+    ("level_4", &[6]),
+    ("level_5", &[7]),
+];
+
+// Sets the metrics for the response `info` from the adaptor endpoint `path`
+
+pub fn record_response(configuration: &Configuration, path: &str, device: [&str; 2], info: &Info) {
+    let enum_metrics = configuration.enum_metrics();
+
+    match path {
+        "common/basic_info" => record_basic_info(device, info),
+        "aircon/get_control_info" => record_control_info(enum_metrics, device, info),
+        "aircon/get_sensor_info" => record_sensor_info(device, info),
+        "aircon/get_week_power" => record_week_power(device, info),
+        "aircon/get_monitordata" => record_monitor_data(device, info),
+        "aircon/get_zone_setting" => record_zone_setting(device, info),
+        "skyfi/common/basic_info" => record_basic_info(device, info),
+        "skyfi/aircon/get_control_info" => record_airbase_control_info(enum_metrics, device, info),
+        "skyfi/aircon/get_sensor_info" => record_sensor_info(device, info),
+        "skyfi/aircon/get_zone_setting" => record_zone_setting(device, info),
+        _ => (),
+    }
+}
+
+// Exports every key of `info` as it was sent, except RAW_SECRET_KEYS.  Numbers go to
+// daikin_raw_value and anything else to daikin_raw_info, replacing the series for the previous
+// value of the key in either metric.  `previous` holds the last value of each endpoint and key.
+fn record_raw(
+    previous: &mut HashMap<(String, String), String>,
+    path: &str,
+    device: [&str; 2],
+    info: &Info,
+) {
+    for (key, value) in info {
+        if RAW_SECRET_KEYS.contains(&key.as_str()) {
+            continue;
+        }
+
+        let labels = [device[0], device[1], path, key];
+        let number = value.parse::<f64>();
+        let old = previous.insert((path.to_string(), key.clone()), value.clone());
+
+        if let Some(old) = old.filter(|old| old != value) {
+            match old.parse::<f64>() {
+                Ok(_) if number.is_err() => {
+                    let _ = RAW_VALUE.remove_label_values(&labels);
+                }
+                Ok(_) => (),
+                Err(_) => {
+                    let _ = RAW_INFO.remove_label_values(&[device[0], device[1], path, key, &old]);
+                }
+            }
+        }
+
+        match number {
+            Ok(number) => RAW_VALUE.with_label_values(&labels).set(number),
+            Err(_) => RAW_INFO
+                .with_label_values(&[device[0], device[1], path, key, value])
+                .set(1),
+        }
+    }
+}
+
+fn record_basic_info(device: [&str; 2], basic_info: &Info) {
+    if let Some(power_on) = basic_info.get("pow") {
+        set_metric!(POWER_ON, power_on, i64, device);
+    }
+}
+
+// Sets the numeric gauge and the state set series for `value` as configured.  Each state of a
+// state set is 1 when `value` is one of its values and 0 otherwise.
+fn record_enum(
+    enum_metrics: EnumMetrics,
+    numeric: &IntGaugeVec,
+    stateset: &IntGaugeVec,
+    states: &[(&str, &[i64])],
+    device: [&str; 2],
+    value: i64,
+) {
+    if enum_metrics.numeric() {
+        numeric.with_label_values(&device).set(value);
+    }
+
+    if enum_metrics.stateset() {
+        for (state, values) in states {
+            stateset
+                .with_label_values(&[device[0], device[1], state])
+                .set(values.contains(&value) as i64);
+        }
+    }
+}
+
+fn record_control_info(enum_metrics: EnumMetrics, device: [&str; 2], control_info: &Info) {
+    if let Some(set_temp) = control_info.get("stemp") {
+        set_metric!(SET_TEMP, set_temp, f64, device);
+    }
+
+    if let Some(set_humid) = control_info.get("shum") {
+        set_metric!(SET_HUMID, set_humid, i64, device);
+    }
+
+    if let Some(mode) = control_info.get("mode") {
+        match mode.parse::<i64>() {
+            Ok(mode) => record_enum(enum_metrics, &MODE, &MODE_STATE, &MODE_STATES, device, mode),
+            Err(_) => error!("Invalid mode {} for {}", mode, device[0]),
+        }
+    }
+
+    if let Some(fan_rate) = control_info.get("f_rate") {
+        let rate = match fan_rate.as_str() {
+            "A" => Ok(1),
+            "B" => Ok(2),
+            _ => fan_rate.parse::<i64>(),
+        };
+
+        match rate {
+            Ok(rate) => record_enum(
+                enum_metrics,
+                &FAN_RATE,
+                &FAN_RATE_STATE,
+                &FAN_RATE_STATES,
+                device,
+                rate,
+            ),
+            Err(_) => error!("Invalid fan rate {} for {}", fan_rate, device[0]),
+        }
+    }
+
+    if let Some(fan_dir) = control_info.get("f_dir") {
+        set_metric!(FAN_DIR, fan_dir, i64, device);
+    }
+}
+
+// AirBase control info uses its own mode and fan rate numbering.  Both are translated to the
+// BRP072A values so daikin_mode and daikin_fan_rate mean the same thing for every adaptor.
+fn record_airbase_control_info(enum_metrics: EnumMetrics, device: [&str; 2], control_info: &Info) {
+    if let Some(set_temp) = control_info.get("stemp") {
+        set_metric!(SET_TEMP, set_temp, f64, device);
+    }
+
+    if let Some(mode) = control_info.get("mode").and_then(|m| airbase_mode(m)) {
+        record_enum(enum_metrics, &MODE, &MODE_STATE, &MODE_STATES, device, mode);
+    }
+
+    if let Some(fan_rate) = control_info.get("f_rate") {
+        let auto = control_info.get("f_auto").map(|f| f.as_str()) == Some("1");
+
+        let fan_rate = match fan_rate.as_str() {
+            _ if auto => Some(1),
+            "1" => Some(3), // low
+            "3" => Some(5), // mid
+            "5" => Some(7), // high
+            _ => None,
+        };
+
+        if let Some(fan_rate) = fan_rate {
+            record_enum(
+                enum_metrics,
+                &FAN_RATE,
+                &FAN_RATE_STATE,
+                &FAN_RATE_STATES,
+                device,
+                fan_rate,
+            );
+        }
+    }
+}
+
+// Translates an AirBase mode to the BRP072A numbering
+pub fn airbase_mode(mode: &str) -> Option<i64> {
+    match mode {
+        "0" => Some(6), // fan
+        "1" => Some(4), // heat
+        "2" => Some(3), // cool
+        "3" => Some(1), // auto
+        "7" => Some(2), // dry
+        _ => None,
+    }
+}
+
+fn record_sensor_info(device: [&str; 2], sensor_info: &Info) {
+    if let Some(unit_temp) = sensor_info.get("htemp") {
+        set_metric!(UNIT_TEMP, unit_temp, f64, device);
+    }
+
+    if let Some(outdoor_temp) = sensor_info.get("otemp") {
+        set_metric!(OUTDOOR_TEMP, outdoor_temp, f64, device);
+    }
+
+    if let Some(compressor_demand) = sensor_info.get("cmpfreq") {
+        set_metric!(COMPRESSOR_DEMAND, compressor_demand, i64, device);
+    }
+}
+
+// Exports the next action of `schedule` in local time, which the adaptor is assumed to share with
+// the exporter
+fn record_schedule(device: [&str; 2], schedule: Option<&Schedule>) {
+    let now = Local::now().naive_local();
+
+    let next = schedule.and_then(|schedule| schedule.next(now));
+
+    let (at, action) = match next {
+        Some(n) => n,
+        None => {
+            let _ = SCHEDULE_NEXT_TIME.remove_label_values(&device);
+            let _ = SCHEDULE_NEXT_POWER_ON.remove_label_values(&device);
+            let _ = SCHEDULE_NEXT_MODE.remove_label_values(&device);
+            return;
+        }
+    };
+
+    if let Some(at) = Local.from_local_datetime(&at).earliest() {
+        SCHEDULE_NEXT_TIME
+            .with_label_values(&device)
+            .set(at.timestamp() as f64);
+    }
+
+    SCHEDULE_NEXT_POWER_ON
+        .with_label_values(&device)
+        .set(action.power_on as i64);
+
+    match action.mode {
+        Some(mode) => SCHEDULE_NEXT_MODE.with_label_values(&device).set(mode),
+        None => {
+            let _ = SCHEDULE_NEXT_MODE.remove_label_values(&device);
+        }
+    }
+}
+
+// Zone names and states are percent-encoded lists separated by ";".  Zones missing from the
+// response, because they were renamed or disabled, are removed.
+fn record_zone_setting(device: [&str; 2], zone_setting: &Info) {
+    let (names, states) = match (
+        zone_setting.get("zone_name"),
+        zone_setting.get("zone_onoff"),
+    ) {
+        (Some(names), Some(states)) => (percent_decode(names), percent_decode(states)),
+        _ => return,
+    };
+
+    let zones: Vec<(&str, &str)> = names
+        .split(';')
+        .map(|name| name.trim())
+        .zip(states.split(';'))
+        .collect();
+
+    for old in zone_labels(device) {
+        if !zones.iter().any(|(name, _)| *name == old) {
+            let _ = ZONE_ON.remove_label_values(&[device[0], device[1], &old]);
+        }
+    }
+
+    for (name, state) in zones {
+        let zone = [device[0], device[1], name];
+
+        if let Ok(on) = state.parse::<i64>() {
+            ZONE_ON.with_label_values(&zone).set(on);
+        } else {
+            error!("Invalid value {} for zone {} {}", state, device[0], name);
+        }
+    }
+}
+
+// Zone names with a daikin_zone_on series for `device`
+fn zone_labels(device: [&str; 2]) -> Vec<String> {
+    let mut zones = vec![];
+
+    for family in ZONE_ON.collect() {
+        for metric in family.get_metric() {
+            let label = |name: &str| {
+                metric
+                    .get_label()
+                    .iter()
+                    .find(|label| label.get_name() == name)
+                    .map(|label| label.get_value().to_string())
+                    .unwrap_or_default()
+            };
+
+            if label("device") == device[0] && label("name") == device[1] {
+                zones.push(label("zone"));
+            }
+        }
+    }
+
+    zones
+}
+
+fn record_week_power(device: [&str; 2], week_power: &Info) {
+    match week_power.get("today_runtime") {
+        Some(daily_runtime) => set_metric!(DAILY_RUNTIME, daily_runtime, i64, device),
+        None => {
+            warn!("Missing today_runtime for {}", device[0]);
+            let _ = DAILY_RUNTIME.remove_label_values(&device);
+        }
+    }
+}
+
+// Monitor data values are hex-encoded ASCII, like "323135" for 215, except the adaptor counters.
+// Temperatures are in tenths of a degree.  Fields without a metric are exported as
+// daikin_monitor_raw, hex-decoded when they decode to a number.
+fn record_monitor_data(device: [&str; 2], monitor_data: &Info) {
+    for (key, value) in monitor_data {
+        match key.as_str() {
+            "ret" => (),
+            "ResetCount" => set_metric!(MONITOR_RESETS, value, i64, device),
+            "RouterDisconCnt" => set_metric!(MONITOR_ROUTER_DISCONNECTS, value, i64, device),
+            "PollingErrCnt" => set_metric!(MONITOR_POLLING_ERRORS, value, i64, device),
+            "fan" | "rawrtmp" | "trtmp" | "hetmp" | "fangl" | "pow" | "mode" => {
+                let decoded = match decode(value) {
+                    Some(d) => d,
+                    None => {
+                        error!("Invalid monitor data {}={} for {}", key, value, device[0]);
+                        continue;
+                    }
+                };
+
+                match key.as_str() {
+                    "fan" => set_metric!(MONITOR_FAN_SPEED, decoded, i64, device),
+                    "rawrtmp" => set_metric_tenth!(MONITOR_RAWRTMP, decoded, f64, device),
+                    "trtmp" => set_metric_tenth!(MONITOR_TRTMP, decoded, f64, device),
+                    "hetmp" => set_metric_tenth!(MONITOR_HETMP, decoded, f64, device),
+                    "fangl" => set_metric!(MONITOR_FANGL, decoded, i64, device),
+                    "pow" => set_metric!(MONITOR_POWER_ON, decoded, i64, device),
+                    _ => set_metric!(MONITOR_MODE, decoded, i64, device),
+                }
+            }
+            _ => {
+                let number = decode(value)
+                    .and_then(|d| d.parse::<f64>().ok())
+                    .or_else(|| value.parse::<f64>().ok());
+
+                match number {
+                    Some(number) => MONITOR_RAW
+                        .with_label_values(&[device[0], device[1], key])
+                        .set(number),
+                    None => debug!("Skipping monitor data {}={} for {}", key, value, device[0]),
+                }
+            }
+        }
+    }
+}
+
 // Decodes "%41%42" to "AB"
 
-fn percent_decode(encoded: &str) -> String {
+pub fn percent_decode(encoded: &str) -> String {
     let mut encoded = encoded.split('%');
 
     encoded.next(); // skip leading empty value
 
-    let decoded = encoded
-        .map(|code| u8::from_str_radix(code, 16).unwrap())
+    let decoded: Vec<u8> = encoded
+        .filter_map(|code| u8::from_str_radix(code, 16).ok())
         .collect();
 
-    String::from_utf8(decoded).unwrap()
+    String::from_utf8_lossy(&decoded).to_string()
 }
 
-// Decodes "4142" to "AB"
+// Encodes "AB" to "%41%42", the way the adaptor encodes names
 
-fn decode(encoded: &str) -> String {
-    let pairs = encoded.len() / 2;
-    let mut decoded = Vec::with_capacity(pairs);
+pub fn percent_encode(decoded: &str) -> String {
+    decoded.bytes().map(|b| format!("%{:02x}", b)).collect()
+}
+
+// Returns an error unless the adaptor answered ret=OK
 
-    for pair in 0..pairs {
-        let offset = pair * 2;
-        decoded.push(u8::from_str_radix(&encoded[offset..offset + 2], 16).unwrap());
+fn check_ret(host: &str, response: &Info) -> Result<()> {
+    match response.get("ret").map(|r| r.as_str()) {
+        Some("OK") => Ok(()),
+        Some(ret) => Err(anyhow!("Daikin adaptor {} answered {}", host, ret)),
+        None => Err(anyhow!("Daikin adaptor {} answered without ret", host)),
     }
+}
 
-    String::from_utf8(decoded).unwrap()
+// Decodes "4142" to "AB".  None when `encoded` isn't hex-encoded UTF-8.
+
+pub fn decode(encoded: &str) -> Option<String> {
+    let decoded = (0..encoded.len())
+        .step_by(2)
+        .map(|offset| {
+            encoded
+                .get(offset..offset + 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+        })
+        .collect::<Option<Vec<u8>>>()?;
+
+    String::from_utf8(decoded).ok()
 }
 
 async fn result_hash(response: reqwest::Response) -> DaikinResponse {
@@ -378,18 +1900,38 @@ async fn result_hash(response: reqwest::Response) -> DaikinResponse {
 
     trace!("Request {} received: {}", url, body);
 
-    let pairs = body.split(',');
+    Ok(parse_response(&body))
+}
 
-    let mut result = HashMap::new();
+// Parses "ret=OK,pow=1" into a map.  Used for both HTTP responses and UDP discovery responses.
+// Entries without a value are skipped.
 
-    for pair in pairs {
-        let mut entry = pair.split('=');
+pub fn parse_response(body: &str) -> Info {
+    body.split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
 
-        let key = entry.next().unwrap().to_string();
-        let value = entry.next().unwrap().to_string();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        result.insert(key, value);
-    }
+    #[test]
+    fn airbase_modes() {
+        let cases = [
+            ("0", Some(6)),
+            ("1", Some(4)),
+            ("2", Some(3)),
+            ("3", Some(1)),
+            ("7", Some(2)),
+            ("4", None),
+            ("", None),
+            ("heat", None),
+        ];
 
-    Ok(result)
+        for (mode, expected) in cases {
+            assert_eq!(expected, airbase_mode(mode), "{}", mode);
+        }
+    }
 }
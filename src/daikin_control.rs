@@ -0,0 +1,195 @@
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+
+use crate::configuration::Configuration;
+use crate::daikin_adaptor;
+use crate::daikin_adaptor::DaikinAdaptor;
+use crate::daikin_discover::DiscoverHandle;
+use crate::daikin_schedule::ScheduleBackup;
+
+use log::info;
+
+use reqwest::Client;
+
+use std::fs;
+use std::sync::Arc;
+
+// One-off commands run instead of the exporter, like `daikin_exporter zone 10.0.0.5 Lounge on`.
+// Per-host settings such as the port or HTTPS key come from the configuration file when one is
+// given before the command.
+
+const COMMANDS: [&str; 3] = ["schedule-backup", "schedule-restore", "zone"];
+
+pub fn is_command(arg: &str) -> bool {
+    COMMANDS.contains(&arg)
+}
+
+pub async fn run(configuration: Configuration, args: &[String]) -> Result<()> {
+    let configuration = Arc::new(configuration);
+
+    match args[0].as_str() {
+        "schedule-backup" => schedule_backup(configuration, &args[1..]).await,
+        "schedule-restore" => schedule_restore(configuration, &args[1..]).await,
+        "zone" => zone(configuration, &args[1..]).await,
+        command => Err(anyhow!("Unknown command {}", command)),
+    }
+}
+
+async fn connect(configuration: Arc<Configuration>, host: &str) -> Result<(DaikinAdaptor, Client)> {
+    let client = daikin_adaptor::client();
+
+    let mut adaptor = DaikinAdaptor::new(
+        host.to_string(),
+        None,
+        configuration,
+        None,
+        None,
+        DiscoverHandle::detached(),
+    );
+
+    adaptor.connect(&client).await?;
+
+    Ok((adaptor, client))
+}
+
+// zone HOST ZONE on|off
+async fn zone(configuration: Arc<Configuration>, args: &[String]) -> Result<()> {
+    let (host, zone, state) = match args {
+        [host, zone, state] => (host, zone, state),
+        _ => {
+            return Err(anyhow!(
+                "Usage: daikin_exporter [CONFIG] zone HOST ZONE on|off"
+            ))
+        }
+    };
+
+    let on = match state.as_str() {
+        "on" => true,
+        "off" => false,
+        _ => return Err(anyhow!("Zone state must be on or off, not {}", state)),
+    };
+
+    let (adaptor, client) = connect(configuration, host).await?;
+
+    adaptor.set_zone(&client, zone, on).await?;
+
+    info!("Turned zone {} {} on {}", zone, state, host);
+
+    Ok(())
+}
+
+// schedule-backup HOST FILE
+//
+// Saves every weekly schedule so it can be restored after the adaptor is reset
+async fn schedule_backup(configuration: Arc<Configuration>, args: &[String]) -> Result<()> {
+    let (host, file) = match args {
+        [host, file] => (host, file),
+        _ => {
+            return Err(anyhow!(
+                "Usage: daikin_exporter [CONFIG] schedule-backup HOST FILE"
+            ))
+        }
+    };
+
+    let (adaptor, client) = connect(configuration, host).await?;
+
+    let info = adaptor
+        .fetch(&client, "aircon/get_scdltimer_info", "")
+        .await?;
+
+    let count = info
+        .get("scdl_num")
+        .and_then(|n| n.parse::<u32>().ok())
+        .unwrap_or(1);
+
+    let mut backup = ScheduleBackup {
+        info: info.into_iter().filter(|(key, _)| key != "ret").collect(),
+        schedules: vec![],
+    };
+
+    for target in 1..=count {
+        let query = format!("target={}", target);
+
+        let body = adaptor
+            .fetch(&client, "aircon/get_scdltimer_body", &query)
+            .await?;
+
+        backup
+            .schedules
+            .push(body.into_iter().filter(|(key, _)| key != "ret").collect());
+    }
+
+    let source = toml::to_string(&backup).context("Unable to serialize schedules")?;
+
+    fs::write(file, source).with_context(|| format!("Unable to write {}", file))?;
+
+    info!(
+        "Saved {} schedules from {} to {}",
+        backup.schedules.len(),
+        host,
+        file
+    );
+
+    Ok(())
+}
+
+// schedule-restore HOST FILE
+async fn schedule_restore(configuration: Arc<Configuration>, args: &[String]) -> Result<()> {
+    let (host, file) = match args {
+        [host, file] => (host, file),
+        _ => {
+            return Err(anyhow!(
+                "Usage: daikin_exporter [CONFIG] schedule-restore HOST FILE"
+            ))
+        }
+    };
+
+    let source = fs::read_to_string(file).with_context(|| format!("Unable to read {}", file))?;
+    let backup: ScheduleBackup =
+        toml::from_str(&source).with_context(|| format!("Unable to parse {}", file))?;
+
+    let (adaptor, client) = connect(configuration, host).await?;
+
+    for (index, schedule) in backup.schedules.iter().enumerate() {
+        let mut query = vec![format!("target={}", index + 1)];
+
+        query.extend(
+            schedule
+                .iter()
+                .filter(|(key, _)| *key != "target")
+                .map(|(key, value)| format!("{}={}", key, value)),
+        );
+
+        let query = query.join("&");
+
+        adaptor
+            .fetch(&client, "aircon/set_scdltimer_body", &query)
+            .await?;
+    }
+
+    // Enable the schedule that was active last
+    let query = ["en_scdltimer", "active_no"]
+        .iter()
+        .filter_map(|key| {
+            backup
+                .info
+                .get(*key)
+                .map(|value| format!("{}={}", key, value))
+        })
+        .collect::<Vec<String>>()
+        .join("&");
+
+    adaptor
+        .fetch(&client, "aircon/set_scdltimer_info", &query)
+        .await?;
+
+    info!(
+        "Restored {} schedules from {} to {}",
+        backup.schedules.len(),
+        file,
+        host
+    );
+
+    Ok(())
+}
@@ -1,10 +1,19 @@
+use anyhow::anyhow;
 use anyhow::Context;
 use anyhow::Result;
 
+use crate::configuration::normalize_mac;
+use crate::daikin_adaptor::parse_response;
+use crate::daikin_adaptor::percent_decode;
+use crate::discover_filter::parse_net;
+use crate::discover_filter::InterfaceFilter;
 use crate::Configuration;
 
 use lazy_static::lazy_static;
 
+use ipnet::IpNet;
+use ipnet::Ipv4Net;
+
 use nix::ifaddrs::getifaddrs;
 use nix::sys::socket::InetAddr;
 use nix::sys::socket::SockAddr;
@@ -12,27 +21,43 @@ use nix::sys::socket::SockAddr;
 use prometheus::register_int_counter_vec;
 use prometheus::IntCounterVec;
 
+use socket2::Domain;
+use socket2::Protocol;
+use socket2::Socket;
+use socket2::Type;
+
+use std::net::IpAddr;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
+use std::time::Instant;
 
 use log::debug;
 use log::error;
 use log::info;
 use log::trace;
+use log::warn;
 
 use tokio::net::UdpSocket;
 use tokio::sync::broadcast;
 use tokio::sync::mpsc;
+use tokio::sync::Notify;
 use tokio::time::interval;
 use tokio::time::sleep;
 use tokio::time::MissedTickBehavior;
 
-type AddressSender = broadcast::Sender<String>;
+type DiscoverSender = broadcast::Sender<DiscoveredUnit>;
 type ErrorSender = mpsc::Sender<anyhow::Error>;
 
 const DISCOVER_PORT: u16 = 30050;
 
+// Discover requests heard in passive mode without hearing any reply before warning that the
+// passive address is probably the request port rather than the reply port
+const PASSIVE_UNANSWERED_REQUESTS: u32 = 10;
+
+// Smallest sweep prefix allowed.  A /22 takes about 20 seconds at the default sweep interval.
+const MIN_SWEEP_PREFIX: u8 = 22;
+
 lazy_static! {
     static ref REQUESTS: IntCounterVec = register_int_counter_vec!(
         "daikin_udp_discover_requests_total",
@@ -42,54 +67,230 @@ lazy_static! {
     .unwrap();
     static ref RESPONSES: IntCounterVec = register_int_counter_vec!(
         "daikin_udp_discover_responses_total",
-        "Number of UDP discover responses read from Daikin adaptors",
-        &["host"],
+        "Number of UDP discover responses read from Daikin adaptors.  The interface is the local interface on the same subnet as the host, which may not be the one the response arrived on.",
+        &["host", "name", "interface"],
     )
     .unwrap();
 }
 
-// Discover daikin units on broadcast addresses
+// A unit that answered a discovery request.  The response is the same as common/basic_info.
+
+#[derive(Clone, Debug)]
+pub struct DiscoveredUnit {
+    pub address: String,
+    pub mac: Option<String>,
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub adaptor_type: Option<String>,
+    // UDP port the adaptor reports for its polling method.  This is not the HTTP port, adaptors
+    // serve HTTP on 80 or 443.
+    pub port: Option<u16>,
+    // Local network interface on the same subnet as the unit
+    pub interface: Option<String>,
+}
+
+impl DiscoveredUnit {
+    pub fn parse(address: String, body: &str) -> Self {
+        let response = parse_response(body);
+
+        let mac = response.get("mac").map(|mac| normalize_mac(mac));
+        let name = response.get("name").map(|name| percent_decode(name));
+        let version = response.get("ver").cloned();
+        let adaptor_type = response.get("type").cloned();
+        let port = response.get("port").and_then(|port| port.parse().ok());
+
+        DiscoveredUnit {
+            address,
+            mac,
+            name,
+            version,
+            adaptor_type,
+            port,
+            interface: None,
+        }
+    }
+}
+
+// Subscribes to discovered units and requests rescans
+
+#[derive(Clone)]
+pub struct DiscoverHandle {
+    channel: DiscoverSender,
+    rescan: Arc<Notify>,
+    subscribed: Arc<Notify>,
+}
+
+impl DiscoverHandle {
+    // A handle not connected to discovery, for one-off commands
+    pub fn detached() -> Self {
+        let (channel, _) = broadcast::channel(1);
+
+        DiscoverHandle {
+            channel,
+            rescan: Arc::new(Notify::new()),
+            subscribed: Arc::new(Notify::new()),
+        }
+    }
+
+    // Discovery broadcasts begin after the first subscriber
+    pub fn subscribe(&self) -> broadcast::Receiver<DiscoveredUnit> {
+        let receiver = self.channel.subscribe();
+
+        self.subscribed.notify_one();
+
+        receiver
+    }
+
+    // Broadcast discover requests now and restart the fast discovery schedule
+    pub fn rescan(&self) {
+        self.rescan.notify_one();
+    }
+}
+
+// Delays between the discover bursts after startup or a rescan.  The ComfortControl app sends
+// bursts a few seconds apart, these are spread over the first minute.
+const FAST_DELAYS: [Duration; 4] = [
+    Duration::from_secs(3),
+    Duration::from_secs(7),
+    Duration::from_secs(20),
+    Duration::from_secs(30),
+];
+
+// First delay after the fast bursts.  The delay doubles up to the major interval.
+const SLOW_DELAY: Duration = Duration::from_secs(60);
+
+const INTERFACE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+// Delays between discover bursts, fast at first then decaying to the major interval
+
+struct Schedule {
+    major_interval: Duration,
+    fast: usize,
+    slow: Duration,
+}
+
+impl Schedule {
+    fn new(major_interval: Duration) -> Self {
+        Schedule {
+            major_interval,
+            fast: 0,
+            slow: SLOW_DELAY,
+        }
+    }
+
+    fn next(&mut self) -> Duration {
+        let delay = match FAST_DELAYS.get(self.fast) {
+            Some(delay) => {
+                self.fast += 1;
+                *delay
+            }
+            None => {
+                let delay = self.slow;
+                self.slow = (self.slow * 2).min(self.major_interval.max(SLOW_DELAY));
+                delay
+            }
+        };
+
+        delay.min(self.major_interval)
+    }
+
+    fn reset(&mut self) {
+        self.fast = 0;
+        self.slow = SLOW_DELAY;
+    }
+}
+
+// Discover daikin units on broadcast addresses, configured directed broadcast addresses, and by
+// unicast to every address in configured networks
 
 #[derive(Clone)]
 pub struct DaikinDiscover {
-    channel: AddressSender,
+    channel: DiscoverSender,
     socket: Arc<UdpSocket>,
+    // Only listen for responses to other clients' discover requests
+    passive: bool,
+    rescan: Arc<Notify>,
+    subscribed: Arc<Notify>,
+
+    addresses: Vec<SocketAddr>,
+    interfaces: InterfaceFilter,
+    sweep: Vec<Ipv4Net>,
 
     major_interval: Duration,
     minor_interval: Duration,
+    sweep_interval: Duration,
 }
 
 impl DaikinDiscover {
     pub async fn new(configuration: &Configuration) -> Result<Self> {
         let major_interval = configuration.discover_major_interval();
         let minor_interval = configuration.discover_minor_interval();
+        let sweep_interval = configuration.discover_sweep_interval();
+
+        let addresses = configuration
+            .discover_addresses()
+            .iter()
+            .map(|address| parse_discover_address(address))
+            .collect::<Result<_>>()?;
+
+        let interfaces = InterfaceFilter::new(configuration)?;
+
+        let sweep = configuration
+            .discover_sweep()
+            .iter()
+            .map(|net| parse_sweep(net))
+            .collect::<Result<_>>()?;
 
         let (channel, _) = broadcast::channel(16);
+        let rescan = Arc::new(Notify::new());
+        let subscribed = Arc::new(Notify::new());
 
-        let socket = UdpSocket::bind(configuration.discover_bind_address())
-            .await
-            .context("Unable to start Daikin discovery")?;
+        let passive = configuration.discover_passive();
 
-        socket
-            .set_broadcast(true)
-            .context("Unable to start Daikin discovery")?;
+        let socket = if passive {
+            let address = configuration.discover_passive_address();
 
-        let socket = Arc::new(socket);
+            let socket =
+                bind_shared(&address).context("Unable to start passive Daikin discovery")?;
+
+            info!("Listening passively for units on {}", address);
+
+            socket
+        } else {
+            let socket = UdpSocket::bind(configuration.discover_bind_address())
+                .await
+                .context("Unable to start Daikin discovery")?;
+
+            socket
+                .set_broadcast(true)
+                .context("Unable to start Daikin discovery")?;
+
+            info!(
+                "Listening for units on {}",
+                configuration.discover_bind_address()
+            );
+
+            socket
+        };
 
-        info!(
-            "Listening for units on {}",
-            configuration.discover_bind_address()
-        );
+        let socket = Arc::new(socket);
 
         Ok(DaikinDiscover {
             channel,
             socket,
+            passive,
+            rescan,
+            subscribed,
+            addresses,
+            interfaces,
+            sweep,
             major_interval,
             minor_interval,
+            sweep_interval,
         })
     }
 
-    pub async fn start(self, error_tx: ErrorSender) -> AddressSender {
+    pub async fn start(self, error_tx: ErrorSender) -> DiscoverHandle {
         let listen_error_tx = error_tx.clone();
         let this = self.clone();
 
@@ -97,43 +298,67 @@ impl DaikinDiscover {
             this.listen_loop(listen_error_tx).await;
         });
 
+        if self.passive {
+            return self.handle();
+        }
+
         let this = self.clone();
         let broadcast_error_tx = error_tx;
 
         tokio::spawn(async move {
-            // wait a bit daikin_watcher has not subscribed yet
-            if this.channel.receiver_count() == 0 {
-                sleep(Duration::from_millis(100)).await;
-            }
-
             this.broadcast_loop(broadcast_error_tx).await;
         });
 
-        self.channel
+        if !self.sweep.is_empty() {
+            let this = self.clone();
+
+            tokio::spawn(async move {
+                this.sweep_loop().await;
+            });
+        }
+
+        self.handle()
+    }
+
+    fn handle(self) -> DiscoverHandle {
+        DiscoverHandle {
+            channel: self.channel,
+            rescan: self.rescan,
+            subscribed: self.subscribed,
+        }
     }
 
     pub async fn broadcast(&self, address: SocketAddr) -> Result<()> {
         trace!("Sending discovery broadcast to {}", address);
 
+        self.send(address, &address.ip().to_string()).await
+    }
+
+    // Sends a discover request to `address` counted under `label`
+    async fn send(&self, address: SocketAddr, label: &str) -> Result<()> {
         self.socket
             .send_to(b"DAIKIN_UDP/common/basic_info", address)
             .await
             .with_context(|| format!("Unable to send discover request to {}", address))?;
 
-        REQUESTS
-            .with_label_values(&[&address.ip().to_string()])
-            .inc();
+        REQUESTS.with_label_values(&[label]).inc();
 
         Ok(())
     }
 
     pub async fn broadcast_loop(&self, error_tx: ErrorSender) {
         debug!("Starting discovery broadcast loop");
-        let mut interval = interval(self.major_interval);
-        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        // Replies arriving before the watcher subscribes would be lost
+        self.subscribed.notified().await;
+
+        let mut schedule = Schedule::new(self.major_interval);
+
+        let mut interface_check = interval(INTERFACE_CHECK_INTERVAL);
+        interface_check.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
         loop {
-            let addresses = match broadcast_addresses() {
+            let local_addresses = match broadcast_addresses(&self.interfaces) {
                 Ok(a) => a,
                 Err(e) => {
                     error_tx
@@ -144,33 +369,100 @@ impl DaikinDiscover {
                 }
             };
 
-            for address in &addresses {
-                if let Err(e) = self.broadcast(*address).await {
-                    error_tx
-                        .send(e)
-                        .await
-                        .expect("Error channel failed unexpectedly, bug?");
-                    return;
-                };
+            if let Err(e) = self.broadcast_burst(&local_addresses).await {
+                error_tx
+                    .send(e)
+                    .await
+                    .expect("Error channel failed unexpectedly, bug?");
+                return;
             }
 
-            sleep(self.minor_interval).await;
+            let delay = sleep(schedule.next());
+            tokio::pin!(delay);
+
+            loop {
+                tokio::select! {
+                    _ = &mut delay => break,
+                    _ = self.rescan.notified() => {
+                        debug!("Discovery rescan requested");
+                        schedule.reset();
+                        break;
+                    }
+                    _ = interface_check.tick() => {
+                        if new_interface(&self.interfaces, &local_addresses) {
+                            info!("Network interface appeared, rescanning for units");
+                            schedule.reset();
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
 
-            for address in addresses {
-                if let Err(e) = self.broadcast(address).await {
-                    error_tx
-                        .send(e)
-                        .await
-                        .expect("Error channel failed unexpectedly, bug?");
-                    return;
-                };
+    // Sends two discover requests to each local broadcast address and configured address, like
+    // the ComfortControl app
+    async fn broadcast_burst(&self, local_addresses: &[SocketAddr]) -> Result<()> {
+        self.broadcast_all(local_addresses).await?;
+
+        sleep(self.minor_interval).await;
+
+        self.broadcast_all(local_addresses).await
+    }
+
+    // Failing to reach a configured address, which may be on a routed network whose route is
+    // flapping, doesn't stop discovery
+    async fn broadcast_all(&self, local_addresses: &[SocketAddr]) -> Result<()> {
+        for address in local_addresses {
+            self.broadcast(*address).await?;
+        }
+
+        for address in &self.addresses {
+            if let Err(e) = self.broadcast(*address).await {
+                warn!("{:#}", e);
             }
+        }
 
+        Ok(())
+    }
+
+    // Sends a unicast discover request to every address in the configured networks, one every
+    // sweep interval.  Requests are counted per network rather than per address.
+    pub async fn sweep_loop(&self) {
+        debug!("Starting discovery sweep loop");
+        let mut interval = interval(self.major_interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
             interval.tick().await;
+
+            for net in &self.sweep {
+                debug!("Sweeping {} for units", net);
+
+                let label = net.to_string();
+
+                for ip in net.hosts() {
+                    let address = SocketAddr::new(IpAddr::V4(ip), DISCOVER_PORT);
+
+                    if let Err(e) = self.send(address, &label).await {
+                        // Keep going so one bad address doesn't stop the sweep
+                        warn!("{:#}", e);
+                    }
+
+                    sleep(self.sweep_interval).await;
+                }
+            }
         }
     }
 
     pub async fn listen(&self) -> Result<()> {
+        let mut networks = local_networks();
+        let mut networks_checked = Instant::now();
+
+        // Other clients' discover requests heard before any reply
+        let mut unanswered = 0;
+        let mut answered = false;
+
         loop {
             let mut buf = vec![0; 1000];
 
@@ -180,18 +472,44 @@ impl DaikinDiscover {
                 .await
                 .context("Unable to read discover response")?;
 
-            RESPONSES.with_label_values(&[&a.ip().to_string()]).inc();
+            let body = String::from_utf8_lossy(&buf[..n]);
 
-            trace!(
-                "received {} bytes {:?} from {}",
-                n,
-                String::from_utf8(buf[..n].to_vec()),
-                a
-            );
+            trace!("received {} bytes {:?} from {}", n, body, a);
+
+            if body.starts_with("DAIKIN_UDP") {
+                // Another client's discover request, heard when listening passively
+                if self.passive && !answered {
+                    unanswered += 1;
+
+                    if unanswered == PASSIVE_UNANSWERED_REQUESTS {
+                        warn!(
+                            "Heard {} discover requests but no replies on {:?}, units reply to the \
+                             requesting client's port so set discover_passive_address to it",
+                            unanswered,
+                            self.socket.local_addr()
+                        );
+                    }
+                }
+
+                continue;
+            }
+
+            answered = true;
+
+            if networks_checked.elapsed() > INTERFACE_CHECK_INTERVAL {
+                networks = local_networks();
+                networks_checked = Instant::now();
+            }
 
             let ip = a.ip().to_string();
+            let mut unit = DiscoveredUnit::parse(ip.clone(), &body);
+            unit.interface = interface_for(&networks, a.ip());
+
+            let name = unit.name.as_deref().unwrap_or("");
+            let interface = unit.interface.as_deref().unwrap_or("");
+            RESPONSES.with_label_values(&[&ip, name, interface]).inc();
 
-            if let Err(e) = self.channel.send(ip.clone()) {
+            if let Err(e) = self.channel.send(unit) {
                 // On startup there may be no subscribers to receive the discovered IP.  Since this
                 // is retried eventually we can wait around for next time.
                 error!("Unable to notify of discovered unit IP {}: {:?}", ip, e);
@@ -214,13 +532,115 @@ impl DaikinDiscover {
     }
 }
 
-// Local broadcast addresses
-fn broadcast_addresses() -> Result<Vec<SocketAddr>> {
+// Binds a UDP socket with SO_REUSEADDR so the port may be shared with other Daikin clients on this
+// host.  SO_REUSEPORT isn't set as it would take replies away from those clients.
+fn bind_shared(address: &str) -> Result<UdpSocket> {
+    let address: SocketAddr = address
+        .parse()
+        .with_context(|| format!("Invalid passive discover address {}", address))?;
+
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&address.into())
+        .with_context(|| format!("Unable to bind {}", address))?;
+
+    let socket = UdpSocket::from_std(socket.into())?;
+
+    Ok(socket)
+}
+
+// Parses a directed broadcast address "10.0.1.255" or "10.0.1.255:30050"
+fn parse_discover_address(address: &str) -> Result<SocketAddr> {
+    if let Ok(address) = address.parse::<SocketAddr>() {
+        return Ok(address);
+    }
+
+    let ip = address
+        .parse::<IpAddr>()
+        .with_context(|| format!("Invalid discover address {}", address))?;
+
+    Ok(SocketAddr::new(ip, DISCOVER_PORT))
+}
+
+// Parses a `discover_sweep` network.  Only IPv4 networks no larger than MIN_SWEEP_PREFIX are
+// allowed so a sweep finishes in reasonable time.
+fn parse_sweep(net: &str) -> Result<Ipv4Net> {
+    let net = match parse_net(net)? {
+        IpNet::V4(net) => net,
+        IpNet::V6(_) => return Err(anyhow!("Discover sweep network {} is not IPv4", net)),
+    };
+
+    if net.prefix_len() < MIN_SWEEP_PREFIX {
+        return Err(anyhow!(
+            "Discover sweep network {} is larger than a /{}",
+            net,
+            MIN_SWEEP_PREFIX
+        ));
+    }
+
+    Ok(net)
+}
+
+// Local IPv4 networks and the interfaces they are on.  Empty if the interfaces can't be read.
+fn local_networks() -> Vec<(IpNet, String)> {
+    let ifaddrs = match getifaddrs() {
+        Ok(ifaddrs) => ifaddrs,
+        Err(e) => {
+            debug!("Unable to find network interfaces: {}", e);
+            return vec![];
+        }
+    };
+
+    ifaddrs
+        .filter_map(|ifaddr| match (ifaddr.address, ifaddr.netmask) {
+            (Some(SockAddr::Inet(address)), Some(SockAddr::Inet(netmask))) => {
+                match (address.ip().to_std(), netmask.ip().to_std()) {
+                    (IpAddr::V4(address), IpAddr::V4(netmask)) => {
+                        let prefix = u32::from(netmask).count_ones() as u8;
+                        let net = Ipv4Net::new(address, prefix).ok()?;
+
+                        Some((IpNet::from(net), ifaddr.interface_name))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+// Local network interface with a network in `networks` containing `ip`.  This is a guess from the
+// subnet, not the interface the response arrived on.
+fn interface_for(networks: &[(IpNet, String)], ip: IpAddr) -> Option<String> {
+    networks
+        .iter()
+        .find(|(net, _)| net.contains(&ip))
+        .map(|(_, interface)| interface.clone())
+}
+
+// True if an interface with a broadcast address not in `known` has appeared
+fn new_interface(interfaces: &InterfaceFilter, known: &[SocketAddr]) -> bool {
+    match broadcast_addresses(interfaces) {
+        Ok(addresses) => addresses.iter().any(|address| !known.contains(address)),
+        Err(_) => false,
+    }
+}
+
+// Local broadcast addresses of interfaces allowed by `interfaces`
+fn broadcast_addresses(interfaces: &InterfaceFilter) -> Result<Vec<SocketAddr>> {
     let ifaddrs = getifaddrs().context("Unable to find network interfaces")?;
 
     let broadcast_addresses = ifaddrs
         .into_iter()
         .filter(|ifaddr| matches!(ifaddr.broadcast, Some(SockAddr::Inet(InetAddr::V4(_)))))
+        .filter(|ifaddr| interfaces.matches(&ifaddr.interface_name))
         .map(|ifaddr| match ifaddr.broadcast.unwrap() {
             SockAddr::Inet(a) => a.ip(),
             other => unreachable!("unhandled broadcast address {:?}, nix bug?", other),
@@ -0,0 +1,229 @@
+use crate::daikin_adaptor::airbase_mode;
+use crate::daikin_adaptor::Info;
+
+// What a unit is doing, as opposed to what it is set to do
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HvacAction {
+    Off,
+    Idle,
+    Heating,
+    Cooling,
+    Drying,
+    Fan,
+}
+
+impl HvacAction {
+    pub const ALL: [HvacAction; 6] = [
+        HvacAction::Off,
+        HvacAction::Idle,
+        HvacAction::Heating,
+        HvacAction::Cooling,
+        HvacAction::Drying,
+        HvacAction::Fan,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HvacAction::Off => "off",
+            HvacAction::Idle => "idle",
+            HvacAction::Heating => "heating",
+            HvacAction::Cooling => "cooling",
+            HvacAction::Drying => "drying",
+            HvacAction::Fan => "fan",
+        }
+    }
+}
+
+// The values from one poll of a unit that the derived metrics are calculated from
+#[derive(Clone, Debug, Default)]
+pub struct Reading {
+    pub power_on: Option<bool>,
+    // BRP072A numbering, AirBase modes are translated
+    pub mode: Option<i64>,
+    pub set_temp: Option<f64>,
+    pub room_temp: Option<f64>,
+    pub compressor_demand: Option<f64>,
+}
+
+impl Reading {
+    // Takes the values from the response `info` from the adaptor endpoint `path`
+    pub fn update(&mut self, path: &str, info: &Info) {
+        let number = |key: &str| info.get(key).and_then(|v| v.parse::<f64>().ok());
+        let airbase = path.starts_with("skyfi/");
+
+        if path.ends_with("common/basic_info") || path.ends_with("aircon/get_control_info") {
+            if let Some(pow) = info.get("pow") {
+                self.power_on = Some(pow == "1");
+            }
+        }
+
+        if path.ends_with("aircon/get_control_info") {
+            self.mode = match info.get("mode") {
+                Some(mode) if airbase => airbase_mode(mode),
+                Some(mode) => mode.parse().ok(),
+                None => self.mode,
+            };
+
+            // stemp is "M" or "--" in modes without a set-point
+            self.set_temp = number("stemp");
+        }
+
+        if path.ends_with("aircon/get_sensor_info") {
+            self.room_temp = number("htemp");
+            self.compressor_demand = number("cmpfreq");
+        }
+    }
+
+    // Temperature set-point minus room temperature.  Positive when the room needs heating.
+    pub fn temperature_delta(&self) -> Option<f64> {
+        Some(self.set_temp? - self.room_temp?)
+    }
+
+    // The action is derived from the mode while the compressor runs.  Units that don't report
+    // compressor demand, like AirBase, never appear idle.  In auto mode the direction comes from
+    // the set-point and room temperature.  None when the reading is incomplete.
+    pub fn action(&self) -> Option<HvacAction> {
+        if !self.power_on? {
+            return Some(HvacAction::Off);
+        }
+
+        let mode = self.mode?;
+
+        if mode == 6 {
+            return Some(HvacAction::Fan);
+        }
+
+        if self.compressor_demand == Some(0.0) {
+            return Some(HvacAction::Idle);
+        }
+
+        match mode {
+            2 => Some(HvacAction::Drying),
+            3 => Some(HvacAction::Cooling),
+            4 => Some(HvacAction::Heating),
+            0 | 1 | 7 => {
+                let delta = self.temperature_delta()?;
+
+                if delta > 0.0 {
+                    Some(HvacAction::Heating)
+                } else if delta < 0.0 {
+                    Some(HvacAction::Cooling)
+                } else {
+                    Some(HvacAction::Idle)
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(
+        power_on: Option<bool>,
+        mode: Option<i64>,
+        temperatures: Option<(f64, f64)>,
+        compressor_demand: Option<f64>,
+    ) -> Reading {
+        Reading {
+            power_on,
+            mode,
+            set_temp: temperatures.map(|(set, _)| set),
+            room_temp: temperatures.map(|(_, room)| room),
+            compressor_demand,
+        }
+    }
+
+    #[test]
+    fn action() {
+        use HvacAction::*;
+
+        let cases = [
+            (reading(Some(false), Some(4), None, Some(38.0)), Some(Off)),
+            (reading(Some(false), None, None, None), Some(Off)),
+            (reading(None, Some(4), None, Some(38.0)), None),
+            (reading(Some(true), None, None, Some(38.0)), None),
+            (reading(Some(true), Some(6), None, Some(0.0)), Some(Fan)),
+            (reading(Some(true), Some(4), None, Some(0.0)), Some(Idle)),
+            (reading(Some(true), Some(2), None, Some(38.0)), Some(Drying)),
+            (
+                reading(Some(true), Some(3), None, Some(38.0)),
+                Some(Cooling),
+            ),
+            (
+                reading(Some(true), Some(4), None, Some(38.0)),
+                Some(Heating),
+            ),
+            // Without compressor demand, like AirBase, the unit is never idle
+            (reading(Some(true), Some(4), None, None), Some(Heating)),
+            // Auto modes take the direction from the temperatures
+            (
+                reading(Some(true), Some(0), Some((22.0, 20.0)), Some(38.0)),
+                Some(Heating),
+            ),
+            (
+                reading(Some(true), Some(1), Some((22.0, 24.0)), Some(38.0)),
+                Some(Cooling),
+            ),
+            (
+                reading(Some(true), Some(7), Some((22.0, 22.0)), Some(38.0)),
+                Some(Idle),
+            ),
+            (reading(Some(true), Some(1), None, Some(38.0)), None),
+            (reading(Some(true), Some(5), None, Some(38.0)), None),
+        ];
+
+        for (reading, expected) in cases {
+            assert_eq!(expected, reading.action(), "{:?}", reading);
+        }
+    }
+
+    #[test]
+    fn update() {
+        let info = |pairs: &[(&str, &str)]| -> Info {
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        };
+
+        let mut reading = Reading::default();
+
+        reading.update("common/basic_info", &info(&[("pow", "1")]));
+        reading.update(
+            "aircon/get_control_info",
+            &info(&[("pow", "1"), ("mode", "3"), ("stemp", "24.0")]),
+        );
+        reading.update(
+            "aircon/get_sensor_info",
+            &info(&[("htemp", "26.5"), ("cmpfreq", "40")]),
+        );
+
+        assert_eq!(Some(true), reading.power_on);
+        assert_eq!(Some(3), reading.mode);
+        assert_eq!(Some(-2.5), reading.temperature_delta());
+        assert_eq!(Some(HvacAction::Cooling), reading.action());
+
+        // Dry mode has no set-point
+        reading.update(
+            "aircon/get_control_info",
+            &info(&[("pow", "1"), ("mode", "2"), ("stemp", "M")]),
+        );
+
+        assert_eq!(None, reading.set_temp);
+        assert_eq!(Some(HvacAction::Drying), reading.action());
+
+        // AirBase modes are translated, heat is 1
+        let mut airbase = Reading::default();
+
+        airbase.update(
+            "skyfi/aircon/get_control_info",
+            &info(&[("pow", "1"), ("mode", "1"), ("stemp", "22")]),
+        );
+
+        assert_eq!(Some(4), airbase.mode);
+        assert_eq!(Some(HvacAction::Heating), airbase.action());
+    }
+}
@@ -0,0 +1,323 @@
+use crate::daikin_adaptor::Info;
+
+use chrono::Datelike;
+use chrono::Duration;
+use chrono::NaiveDateTime;
+use chrono::NaiveTime;
+use chrono::Weekday;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+// Keys of the day entries in aircon/get_scdltimer_body
+const DAYS: [(&str, Weekday); 7] = [
+    ("moc", Weekday::Mon),
+    ("tuc", Weekday::Tue),
+    ("wec", Weekday::Wed),
+    ("thc", Weekday::Thu),
+    ("frc", Weekday::Fri),
+    ("sac", Weekday::Sat),
+    ("suc", Weekday::Sun),
+];
+
+// Entry layout used when aircon/get_scdltimer_info doesn't describe one in f_detail
+const DEFAULT_DETAIL: &str = "total#18;_en#1;_pow#1;_mode#1;_temp#4;_time#4;_vamt#1;_vfan#1";
+
+// A scheduled change of the unit's state
+#[derive(Clone, Debug, PartialEq)]
+pub struct Action {
+    pub weekday: Weekday,
+    pub time: NaiveTime,
+    pub power_on: bool,
+    pub mode: Option<i64>,
+}
+
+// A weekly schedule.  Each day is a string of fixed-width entries described by f_detail, like
+// "total#18;_en#1;_pow#1;_mode#1;_temp#4;_time#4;_vamt#1;_vfan#1".  `_time` is HHMM in the
+// adaptor's local time and unused entries are filled with "-".
+
+#[derive(Clone, Debug, Default)]
+pub struct Schedule {
+    pub actions: Vec<Action>,
+}
+
+impl Schedule {
+    pub fn parse(detail: Option<&str>, body: &Info) -> Self {
+        let layout = Layout::parse(detail.unwrap_or(DEFAULT_DETAIL));
+
+        let mut actions = vec![];
+
+        for (key, weekday) in DAYS {
+            let day = match body.get(key) {
+                Some(d) => d,
+                None => continue,
+            };
+
+            let entries = day.as_bytes().chunks(layout.total);
+
+            for entry in entries {
+                let entry = String::from_utf8_lossy(entry);
+
+                if let Some(action) = layout.action(weekday, &entry) {
+                    actions.push(action);
+                }
+            }
+        }
+
+        actions.sort_by_key(|action| (action.weekday.num_days_from_monday(), action.time));
+
+        Schedule { actions }
+    }
+
+    // The first action after `now` and when it happens
+    pub fn next(&self, now: NaiveDateTime) -> Option<(NaiveDateTime, &Action)> {
+        for offset in 0..=7 {
+            let date = now.date() + Duration::days(offset);
+
+            let next = self
+                .actions
+                .iter()
+                .filter(|action| action.weekday == date.weekday())
+                .map(|action| (date.and_time(action.time), action))
+                .find(|(at, _)| *at > now);
+
+            if next.is_some() {
+                return next;
+            }
+        }
+
+        None
+    }
+}
+
+// The schedule body last read from an adaptor
+#[derive(Clone)]
+pub struct ActiveSchedule {
+    // active_no from aircon/get_scdltimer_info
+    pub number: String,
+    pub fetched: Instant,
+    pub schedule: Schedule,
+}
+
+// Field offsets within a schedule entry
+struct Layout {
+    total: usize,
+    fields: BTreeMap<String, (usize, usize)>,
+}
+
+impl Layout {
+    fn parse(detail: &str) -> Self {
+        let mut total = 0;
+        let mut offset = 0;
+        let mut fields = BTreeMap::new();
+
+        for field in detail.split(';') {
+            let (name, width) = match field.split_once('#') {
+                Some((name, width)) => match width.parse::<usize>() {
+                    Ok(width) => (name, width),
+                    Err(_) => continue,
+                },
+                None => continue,
+            };
+
+            if name == "total" {
+                total = width;
+            } else {
+                fields.insert(name.trim_start_matches('_').to_string(), (offset, width));
+                offset += width;
+            }
+        }
+
+        Layout {
+            total: total.max(offset).max(1),
+            fields,
+        }
+    }
+
+    fn field<'a>(&self, entry: &'a str, name: &str) -> Option<&'a str> {
+        let (offset, width) = self.fields.get(name)?;
+
+        entry.get(*offset..offset + width)
+    }
+
+    fn action(&self, weekday: Weekday, entry: &str) -> Option<Action> {
+        if self.field(entry, "en")? != "1" {
+            return None;
+        }
+
+        let power_on = self.field(entry, "pow")? == "1";
+        let mode = self.field(entry, "mode").and_then(|m| m.parse().ok());
+
+        let time = self.field(entry, "time")?;
+        let time = NaiveTime::from_hms_opt(
+            time.get(0..2)?.parse().ok()?,
+            time.get(2..4)?.parse().ok()?,
+            0,
+        )?;
+
+        Some(Action {
+            weekday,
+            time,
+            power_on,
+            mode,
+        })
+    }
+}
+
+// A schedule backup file.  Values are stored exactly as the adaptor sent them so they can be sent
+// back unchanged.
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ScheduleBackup {
+    // aircon/get_scdltimer_info
+    pub info: BTreeMap<String, String>,
+    // aircon/get_scdltimer_body for each schedule
+    #[serde(default, rename = "schedule")]
+    pub schedules: Vec<BTreeMap<String, String>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::NaiveDate;
+
+    // en, pow, mode, temp, time, vamt and vfan padded to 18
+    const ON_0700: &str = "1140220070000-----";
+    const OFF_2200: &str = "1040220220000-----";
+    const DISABLED_1200: &str = "0140220120000-----";
+    const EMPTY: &str = "------------------";
+
+    fn body(days: &[(&str, String)]) -> Info {
+        days.iter()
+            .map(|(key, value)| (key.to_string(), value.clone()))
+            .collect()
+    }
+
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        // 2024-01-01 is a Monday
+        NaiveDate::from_ymd_opt(2024, 1, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        let body = body(&[
+            ("format", "v1".to_string()),
+            ("suc", format!("{}{}", OFF_2200, ON_0700)),
+            ("moc", format!("{}{}{}", ON_0700, DISABLED_1200, EMPTY)),
+        ]);
+
+        let schedule = Schedule::parse(None, &body);
+
+        let actions: Vec<(Weekday, NaiveTime, bool, Option<i64>)> = schedule
+            .actions
+            .iter()
+            .map(|a| (a.weekday, a.time, a.power_on, a.mode))
+            .collect();
+
+        assert_eq!(
+            vec![
+                (Weekday::Mon, time(7, 0), true, Some(4)),
+                (Weekday::Sun, time(7, 0), true, Some(4)),
+                (Weekday::Sun, time(22, 0), false, Some(4)),
+            ],
+            actions
+        );
+    }
+
+    #[test]
+    fn parse_detail() {
+        let body = body(&[("tuc", "1130730".to_string())]);
+
+        let schedule = Schedule::parse(Some("total#4;_en#1;_pow#1;_mode#1;_time#4"), &body);
+
+        assert_eq!(
+            vec![Action {
+                weekday: Weekday::Tue,
+                time: time(7, 30),
+                power_on: true,
+                mode: Some(3),
+            }],
+            schedule.actions
+        );
+    }
+
+    #[test]
+    fn parse_malformed() {
+        let cases = [
+            (None, "11"),
+            (None, "1140220xx00000-----"),
+            (None, "1140220250000-----"),
+            (None, "1140220070"),
+            (Some("total#x;_en;garbage"), ON_0700),
+            (Some(""), ON_0700),
+        ];
+
+        for (detail, day) in cases {
+            let body = body(&[("moc", day.to_string())]);
+
+            let schedule = Schedule::parse(detail, &body);
+
+            assert!(schedule.actions.is_empty(), "{:?} {}", detail, day);
+        }
+    }
+
+    #[test]
+    fn next() {
+        let body = body(&[
+            ("moc", format!("{}{}", ON_0700, OFF_2200)),
+            ("wec", ON_0700.to_string()),
+            ("suc", OFF_2200.to_string()),
+        ]);
+
+        let schedule = Schedule::parse(None, &body);
+
+        let cases = [
+            // Later the same day
+            (at(1, 6, 0), at(1, 7, 0)),
+            (at(1, 7, 0), at(1, 22, 0)),
+            // Wraps to a later day
+            (at(1, 22, 0), at(3, 7, 0)),
+            (at(2, 12, 0), at(3, 7, 0)),
+            // Wraps past the end of the week
+            (at(7, 22, 0), at(8, 7, 0)),
+            (at(7, 23, 0), at(8, 7, 0)),
+        ];
+
+        for (now, expected) in cases {
+            let (next, _) = schedule.next(now).unwrap();
+
+            assert_eq!(expected, next, "{}", now);
+        }
+    }
+
+    #[test]
+    fn next_wraps_to_same_day() {
+        let body = body(&[("wec", ON_0700.to_string())]);
+
+        let schedule = Schedule::parse(None, &body);
+
+        let (next, action) = schedule.next(at(3, 8, 0)).unwrap();
+
+        assert_eq!(at(10, 7, 0), next);
+        assert!(action.power_on);
+    }
+
+    #[test]
+    fn next_empty() {
+        let body = body(&[("moc", EMPTY.repeat(2))]);
+
+        let schedule = Schedule::parse(None, &body);
+
+        assert_eq!(None, schedule.next(at(1, 0, 0)));
+    }
+}
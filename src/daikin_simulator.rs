@@ -0,0 +1,1823 @@
+// Simulates a Daikin BRP072A adaptor on localhost.  It answers the UDP discovery protocol and
+// serves the HTTP endpoints the exporter polls so discovery, the watcher and the exporter can be
+// tested end to end without hardware.
+
+use crate::configuration::Configuration;
+use crate::daikin_adaptor;
+use crate::daikin_adaptor::percent_decode;
+use crate::daikin_adaptor::percent_encode;
+use crate::daikin_control;
+use crate::daikin_discover::DaikinDiscover;
+use crate::daikin_exporter::DaikinExporter;
+use crate::daikin_schedule::ScheduleBackup;
+use crate::daikin_watcher::DaikinWatcher;
+use crate::pcap_replay::metric_value;
+
+use chrono::Local;
+use chrono::NaiveDateTime;
+use chrono::NaiveTime;
+use chrono::TimeZone;
+use chrono::Utc;
+
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::x509::X509NameBuilder;
+use openssl::x509::X509;
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+use tokio::time::Instant;
+
+use tokio_native_tls::native_tls;
+use tokio_native_tls::TlsAcceptor;
+
+// State of the simulated unit and the faults to inject
+
+#[derive(Clone)]
+struct Unit {
+    mac: String,
+    name: String,
+
+    pow: String,
+    mode: String,
+    stemp: String,
+    shum: String,
+    f_rate: String,
+    f_dir: String,
+
+    htemp: String,
+    otemp: String,
+    cmpfreq: String,
+    today_runtime: String,
+
+    zone_names: Vec<String>,
+    zone_states: Vec<String>,
+
+    // aircon/get_scdltimer_info and the body of each schedule
+    schedule_info: BTreeMap<String, String>,
+    schedules: Vec<BTreeMap<String, String>>,
+
+    // Difference between the adaptor clock and the host clock
+    clock_offset: chrono::Duration,
+    // Answer common/notify_date_time without setting the clock, like an adaptor in another zone
+    clock_locked: bool,
+
+    // Delay before answering each HTTP request
+    latency: Duration,
+    // Accept HTTP connections but never answer
+    unresponsive: bool,
+    // Replacement values for (path, key), used to send malformed fields
+    overrides: HashMap<(String, String), String>,
+    // (path, key) left out of responses
+    removed: HashSet<(String, String)>,
+
+    // Key required by common/register_terminal.  When set only registered uuids are answered.
+    key: Option<String>,
+    registered: HashSet<String>,
+
+    // Serve the AirBase (BRP15B61) API under /skyfi instead of the BRP072A API
+    airbase: bool,
+}
+
+impl Unit {
+    fn new(mac: &str, name: &str) -> Self {
+        Unit {
+            mac: mac.to_string(),
+            name: name.to_string(),
+            pow: "1".to_string(),
+            mode: "4".to_string(),
+            stemp: "22.5".to_string(),
+            shum: "0".to_string(),
+            f_rate: "A".to_string(),
+            f_dir: "0".to_string(),
+            htemp: "20.0".to_string(),
+            otemp: "5.0".to_string(),
+            cmpfreq: "38".to_string(),
+            today_runtime: "42".to_string(),
+            zone_names: vec!["Lounge".into(), "Bed 2".into(), "Zone 3".into()],
+            zone_states: vec!["1".into(), "0".into(), "0".into()],
+            schedule_info: default_schedule_info(),
+            schedules: default_schedules(),
+            clock_offset: chrono::Duration::zero(),
+            clock_locked: false,
+            latency: Duration::ZERO,
+            unresponsive: false,
+            overrides: HashMap::new(),
+            removed: HashSet::new(),
+            key: None,
+            registered: HashSet::new(),
+            airbase: false,
+        }
+    }
+
+    // Response body for `path`, None for unknown endpoints
+    fn response(&self, path: &str) -> Option<String> {
+        let api = match (self.airbase, path.strip_prefix("skyfi/")) {
+            (true, Some(api)) => api,
+            (false, None) => path,
+            _ => return None,
+        };
+
+        let fields: Vec<(&str, String)> = match api {
+            "common/basic_info" => vec![
+                ("ret", "OK".to_string()),
+                ("type", "aircon".to_string()),
+                ("reg", "us".to_string()),
+                ("dst", "1".to_string()),
+                ("ver", "2_9_0".to_string()),
+                ("pow", self.pow.clone()),
+                ("err", "0".to_string()),
+                ("location", "0".to_string()),
+                ("name", percent_encode(&self.name)),
+                ("icon", "5".to_string()),
+                ("method", "polling".to_string()),
+                ("port", "30050".to_string()),
+                ("id", "".to_string()),
+                ("pw", "".to_string()),
+                ("lpw_flag", "0".to_string()),
+                ("adp_kind", "2".to_string()),
+                ("pv", "0".to_string()),
+                ("cpv", "0".to_string()),
+                ("cpv_minor", "00".to_string()),
+                ("led", "0".to_string()),
+                ("en_setzone", "1".to_string()),
+                ("mac", self.mac.clone()),
+                ("adp_mode", "run".to_string()),
+                ("en_hol", "0".to_string()),
+                ("grp_name", "".to_string()),
+                ("en_grp", "0".to_string()),
+            ],
+            "aircon/get_control_info" if self.airbase => vec![
+                ("ret", "OK".to_string()),
+                ("pow", self.pow.clone()),
+                ("mode", "2".to_string()),
+                ("operate", "2".to_string()),
+                ("stemp", self.stemp.clone()),
+                ("f_rate", "3".to_string()),
+                ("f_auto", "0".to_string()),
+                ("f_airside", "0".to_string()),
+            ],
+            "aircon/get_zone_setting" => vec![
+                ("ret", "OK".to_string()),
+                ("zone_name", percent_encode(&self.zone_names.join(";"))),
+                ("zone_onoff", percent_encode(&self.zone_states.join(";"))),
+            ],
+            "aircon/get_control_info" => vec![
+                ("ret", "OK".to_string()),
+                ("pow", self.pow.clone()),
+                ("mode", self.mode.clone()),
+                ("adv", "".to_string()),
+                ("stemp", self.stemp.clone()),
+                ("shum", self.shum.clone()),
+                ("alert", "255".to_string()),
+                ("f_rate", self.f_rate.clone()),
+                ("f_dir", self.f_dir.clone()),
+            ],
+            "aircon/get_sensor_info" => vec![
+                ("ret", "OK".to_string()),
+                ("htemp", self.htemp.clone()),
+                ("hhum", "-".to_string()),
+                ("otemp", self.otemp.clone()),
+                ("err", "0".to_string()),
+                ("cmpfreq", self.cmpfreq.clone()),
+            ],
+            "common/get_progsum" => vec![("ret", "OK".to_string()), ("csum", "36085".to_string())],
+            "aircon/get_model_info" => vec![
+                ("ret", "OK".to_string()),
+                ("model", "NOTSUPPORT".to_string()),
+                ("type", "N".to_string()),
+                ("pv", "0".to_string()),
+                ("cpv", "0".to_string()),
+                ("mid", "NA".to_string()),
+                ("s_fdir", "3".to_string()),
+                ("en_scdltmr", "1".to_string()),
+            ],
+            "aircon/get_week_power" => vec![
+                ("ret", "OK".to_string()),
+                ("today_runtime", self.today_runtime.clone()),
+                ("datas", "0/0/0/0/0/0/0".to_string()),
+            ],
+            "aircon/get_monitordata" => vec![
+                ("ret", "OK".to_string()),
+                ("tap", hex_encode("0")),
+                ("pow", hex_encode(&self.pow)),
+                ("mode", hex_encode(&self.mode)),
+                ("fan", hex_encode("50")),
+                ("rawrtmp", hex_encode("215")),
+                ("trtmp", hex_encode("200")),
+                ("fangl", hex_encode("3")),
+                ("hetmp", hex_encode("305")),
+                // Not understood by the exporter
+                ("dmnd", hex_encode("38")),
+                ("ResetCount", "3".to_string()),
+                ("RouterDisconCnt", "1".to_string()),
+                ("PollingErrCnt", "0".to_string()),
+            ],
+            _ => return None,
+        };
+
+        let body = fields
+            .into_iter()
+            .filter(|(key, _)| !self.removed.contains(&(path.to_string(), key.to_string())))
+            .map(|(key, value)| {
+                let value = self
+                    .overrides
+                    .get(&(path.to_string(), key.to_string()))
+                    .cloned()
+                    .unwrap_or(value);
+
+                format!("{}={}", key, value)
+            })
+            .collect::<Vec<String>>()
+            .join(",");
+
+        Some(body)
+    }
+
+    // Applies aircon/set_control_info parameters
+    fn set_control_info(&mut self, query: &str) -> String {
+        for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+            let value = value.to_string();
+
+            match key {
+                "pow" => self.pow = value,
+                "mode" => self.mode = value,
+                "stemp" => self.stemp = value,
+                "shum" => self.shum = value,
+                "f_rate" => self.f_rate = value,
+                "f_dir" => self.f_dir = value,
+                _ => return "ret=PARAM NG".to_string(),
+            }
+        }
+
+        "ret=OK".to_string()
+    }
+
+    // Answers common/get_datetime with local time and sets the clock from common/notify_date_time,
+    // None for other paths
+    fn clock(&mut self, path: &str, query: &str) -> Option<String> {
+        match path {
+            "common/get_datetime" => {
+                let current = Local::now() + self.clock_offset;
+
+                Some(format!(
+                    "ret=OK,sta=2,cur={},reg=us,dst=1,zone=201",
+                    current.format("%Y/%m/%d %H:%M:%S")
+                ))
+            }
+            "common/notify_date_time" => {
+                let params: HashMap<&str, String> = query
+                    .split('&')
+                    .filter_map(|pair| pair.split_once('='))
+                    .map(|(key, value)| (key, value.replace("%3A", ":")))
+                    .collect();
+
+                let time = format!("{} {}", params.get("date")?, params.get("time")?);
+                let time = NaiveDateTime::parse_from_str(&time, "%Y/%m/%d %H:%M:%S").ok()?;
+
+                if !self.clock_locked {
+                    self.clock_offset = Utc.from_utc_datetime(&time) - Utc::now();
+                }
+
+                Some("ret=OK".to_string())
+            }
+            _ => None,
+        }
+    }
+
+    // Answers the aircon/*_scdltimer_* endpoints, None for other paths
+    fn schedule(&mut self, path: &str, query: &str) -> Option<String> {
+        let params: BTreeMap<String, String> = query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+
+        let target = params
+            .get("target")
+            .and_then(|t| t.parse::<usize>().ok())
+            .filter(|t| *t >= 1 && *t <= self.schedules.len());
+
+        let fields = match (path, target) {
+            ("aircon/get_scdltimer_info", _) => self.schedule_info.clone(),
+            ("aircon/get_scdltimer_body", Some(target)) => {
+                let mut body = self.schedules[target - 1].clone();
+                body.insert("target".to_string(), target.to_string());
+                body
+            }
+            ("aircon/set_scdltimer_body", Some(target)) => {
+                self.schedules[target - 1] = params;
+                BTreeMap::new()
+            }
+            ("aircon/set_scdltimer_info", _) => {
+                self.schedule_info.extend(params);
+                BTreeMap::new()
+            }
+            ("aircon/get_scdltimer_body", None) | ("aircon/set_scdltimer_body", None) => {
+                return Some("ret=PARAM NG".to_string())
+            }
+            _ => return None,
+        };
+
+        let mut body = vec!["ret=OK".to_string()];
+        body.extend(
+            fields
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value)),
+        );
+
+        Some(body.join(","))
+    }
+
+    // Applies aircon/set_zone_setting, which sets every zone at once
+    fn set_zone_setting(&mut self, query: &str) -> String {
+        let mut names = None;
+        let mut states = None;
+
+        for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+            match key {
+                "zone_name" => names = Some(percent_decode(value)),
+                "zone_onoff" => states = Some(percent_decode(value)),
+                "lpw" => (),
+                _ => return "ret=PARAM NG".to_string(),
+            }
+        }
+
+        match (names, states) {
+            (Some(names), Some(states)) => {
+                self.zone_names = names.split(';').map(|n| n.to_string()).collect();
+                self.zone_states = states.split(';').map(|s| s.to_string()).collect();
+
+                "ret=OK".to_string()
+            }
+            _ => "ret=PARAM NG".to_string(),
+        }
+    }
+
+    // Applies common/register_terminal
+    fn register_terminal(&mut self, query: &str, uuid: Option<&str>) -> String {
+        let key = query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == "key")
+            .map(|(_, value)| value);
+
+        match (key, uuid) {
+            (Some(key), Some(uuid)) if self.key.as_deref() == Some(key) => {
+                self.registered.insert(uuid.to_string());
+                "ret=OK".to_string()
+            }
+            _ => "ret=PARAM NG".to_string(),
+        }
+    }
+
+    // Requests from unregistered uuids are forbidden when a key is required
+    fn authorized(&self, uuid: Option<&str>) -> bool {
+        match &self.key {
+            Some(_) => uuid.is_some_and(|uuid| self.registered.contains(uuid)),
+            None => true,
+        }
+    }
+}
+
+// Last octet of the loopback address of the next simulator
+static NEXT_ADDRESS: AtomicU8 = AtomicU8::new(2);
+
+pub struct DaikinSimulator {
+    unit: Arc<Mutex<Unit>>,
+    pub http_address: SocketAddr,
+    pub udp_address: SocketAddr,
+}
+
+impl DaikinSimulator {
+    pub async fn start(mac: &str, name: &str) -> Self {
+        Self::start_with(mac, name, None).await
+    }
+
+    // Serves HTTPS with a self-signed certificate, like BRP072C adaptors
+    pub async fn start_https(mac: &str, name: &str) -> Self {
+        let acceptor = native_tls::TlsAcceptor::new(self_signed_identity()).unwrap();
+
+        Self::start_with(mac, name, Some(TlsAcceptor::from(acceptor))).await
+    }
+
+    async fn start_with(mac: &str, name: &str, tls: Option<TlsAcceptor>) -> Self {
+        let unit = Arc::new(Mutex::new(Unit::new(mac, name)));
+
+        // Each simulator gets its own loopback address so the host label of its series is unique
+        let address = format!("127.0.0.{}", NEXT_ADDRESS.fetch_add(1, Ordering::Relaxed));
+
+        let http = TcpListener::bind((address.as_str(), 0)).await.unwrap();
+        let http_address = http.local_addr().unwrap();
+
+        let udp = UdpSocket::bind((address.as_str(), 0)).await.unwrap();
+        let udp_address = udp.local_addr().unwrap();
+
+        let http_unit = unit.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = http.accept().await.unwrap();
+                let unit = http_unit.clone();
+                let tls = tls.clone();
+
+                tokio::spawn(async move {
+                    match tls {
+                        Some(tls) => {
+                            if let Ok(stream) = tls.accept(stream).await {
+                                serve_http(stream, unit).await;
+                            }
+                        }
+                        None => serve_http(stream, unit).await,
+                    }
+                });
+            }
+        });
+
+        let udp_unit = unit.clone();
+
+        tokio::spawn(async move {
+            serve_udp(udp, udp_unit).await;
+        });
+
+        DaikinSimulator {
+            unit,
+            http_address,
+            udp_address,
+        }
+    }
+
+    pub fn set_latency(&self, latency: Duration) {
+        self.unit.lock().unwrap().latency = latency;
+    }
+
+    pub fn set_unresponsive(&self, unresponsive: bool) {
+        self.unit.lock().unwrap().unresponsive = unresponsive;
+    }
+
+    pub fn set_name(&self, name: &str) {
+        self.unit.lock().unwrap().name = name.to_string();
+    }
+
+    pub fn set_clock_offset(&self, offset: chrono::Duration) {
+        self.unit.lock().unwrap().clock_offset = offset;
+    }
+
+    pub fn set_clock_locked(&self, locked: bool) {
+        self.unit.lock().unwrap().clock_locked = locked;
+    }
+
+    pub fn clock_offset(&self) -> chrono::Duration {
+        self.unit.lock().unwrap().clock_offset
+    }
+
+    pub fn schedules(&self) -> Vec<BTreeMap<String, String>> {
+        self.unit.lock().unwrap().schedules.clone()
+    }
+
+    pub fn clear_schedules(&self) {
+        let mut unit = self.unit.lock().unwrap();
+
+        for schedule in unit.schedules.iter_mut() {
+            for value in schedule.values_mut() {
+                if value.starts_with('1') || value.starts_with('0') {
+                    *value = "-".repeat(value.len());
+                }
+            }
+        }
+    }
+
+    pub fn set_airbase(&self) {
+        self.unit.lock().unwrap().airbase = true;
+    }
+
+    // Requires HTTP clients to register with `key` before they are answered
+    pub fn set_key(&self, key: &str) {
+        self.unit.lock().unwrap().key = Some(key.to_string());
+    }
+
+    // Replaces the value of `key` in responses from `path`
+    pub fn set_field(&self, path: &str, key: &str, value: &str) {
+        self.unit
+            .lock()
+            .unwrap()
+            .overrides
+            .insert((path.to_string(), key.to_string()), value.to_string());
+    }
+
+    // Leaves `key` out of responses from `path`
+    pub fn remove_field(&self, path: &str, key: &str) {
+        self.unit
+            .lock()
+            .unwrap()
+            .removed
+            .insert((path.to_string(), key.to_string()));
+    }
+}
+
+async fn serve_http<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, unit: Arc<Mutex<Unit>>) {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+
+    while !request.ends_with(b"\r\n\r\n") {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    let request = String::from_utf8_lossy(&request).to_string();
+    let target = request.split(' ').nth(1).unwrap_or("/");
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let path = path.trim_start_matches('/');
+
+    let uuid = request.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+
+        if name.eq_ignore_ascii_case("X-Daikin-uuid") {
+            Some(value.trim().to_string())
+        } else {
+            None
+        }
+    });
+    let uuid = uuid.as_deref();
+
+    let (latency, unresponsive) = {
+        let unit = unit.lock().unwrap();
+        (unit.latency, unit.unresponsive)
+    };
+
+    if unresponsive {
+        sleep(Duration::from_secs(3600)).await;
+        return;
+    }
+
+    sleep(latency).await;
+
+    let body = {
+        let mut unit = unit.lock().unwrap();
+
+        if path == "common/register_terminal" {
+            Some(Ok(unit.register_terminal(query, uuid)))
+        } else if !unit.authorized(uuid) {
+            Some(Err(()))
+        } else if unit.airbase
+            && path.starts_with("skyfi/")
+            && !query.split('&').any(|pair| pair.starts_with("lpw="))
+        {
+            Some(Ok("ret=PARAM NG".to_string()))
+        } else if path == "aircon/set_control_info" {
+            Some(Ok(unit.set_control_info(query)))
+        } else if let Some(body) = unit.clock(path, query) {
+            Some(Ok(body))
+        } else if let Some(body) = unit.schedule(path, query) {
+            Some(Ok(body))
+        } else if path.ends_with("aircon/set_zone_setting") {
+            Some(Ok(unit.set_zone_setting(query)))
+        } else {
+            unit.response(path).map(Ok)
+        }
+    };
+
+    // The adaptor answers with HTTP/1.0 and closes the connection
+    let response = match body {
+        Some(Ok(body)) => format!(
+            "HTTP/1.0 200 OK\r\nContent-Length: {}\r\nContent-Type: text/plain\r\n\r\n{}",
+            body.len(),
+            body
+        ),
+        Some(Err(())) => "HTTP/1.0 403 Forbidden\r\nContent-Length: 0\r\n\r\n".to_string(),
+        None => "HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_string(),
+    };
+
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+// A certificate and key like the one an HTTPS adaptor generates for itself
+fn self_signed_identity() -> native_tls::Identity {
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "daikin-simulator").unwrap();
+    let name = name.build();
+
+    let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+
+    let mut certificate = X509::builder().unwrap();
+    certificate.set_version(2).unwrap();
+    certificate.set_serial_number(&serial).unwrap();
+    certificate.set_subject_name(&name).unwrap();
+    certificate.set_issuer_name(&name).unwrap();
+    certificate.set_pubkey(&key).unwrap();
+    certificate
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    certificate
+        .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    certificate.sign(&key, MessageDigest::sha256()).unwrap();
+    let certificate = certificate.build();
+
+    native_tls::Identity::from_pkcs8(
+        &certificate.to_pem().unwrap(),
+        &key.private_key_to_pem_pkcs8().unwrap(),
+    )
+    .unwrap()
+}
+
+async fn serve_udp(socket: UdpSocket, unit: Arc<Mutex<Unit>>) {
+    let mut buf = [0; 1000];
+
+    loop {
+        let (n, address) = socket.recv_from(&mut buf).await.unwrap();
+
+        if &buf[..n] != b"DAIKIN_UDP/common/basic_info" {
+            continue;
+        }
+
+        let body = {
+            let unit = unit.lock().unwrap();
+
+            let path = match unit.airbase {
+                true => "skyfi/common/basic_info",
+                false => "common/basic_info",
+            };
+
+            unit.response(path).unwrap()
+        };
+
+        socket.send_to(body.as_bytes(), address).await.unwrap();
+    }
+}
+
+fn default_schedule_info() -> BTreeMap<String, String> {
+    [
+        ("format", "v1"),
+        (
+            "f_detail",
+            "total#18;_en#1;_pow#1;_mode#1;_temp#4;_time#4;_vamt#1;_vfan#1",
+        ),
+        ("en_scdltimer", "1"),
+        ("active_no", "1"),
+        ("scdl_num", "2"),
+        ("scdl_per_day", "2"),
+    ]
+    .iter()
+    .map(|(key, value)| (key.to_string(), value.to_string()))
+    .collect()
+}
+
+// Schedule 1 turns the unit on in heat at 07:00 and off at 22:00 every day.  Schedule 2 is empty.
+fn default_schedules() -> Vec<BTreeMap<String, String>> {
+    let days = ["moc", "tuc", "wec", "thc", "frc", "sac", "suc"];
+
+    let schedule = |entries: &str| {
+        let mut schedule: BTreeMap<String, String> = days
+            .iter()
+            .map(|day| (day.to_string(), entries.to_string()))
+            .collect();
+
+        schedule.insert("format".to_string(), "v1".to_string());
+
+        schedule
+    };
+
+    // en, pow, mode, temp, time, vamt and vfan padded to 18
+    let on = "1140220070000-----";
+    let off = "1040220220000-----";
+
+    vec![
+        schedule(&format!("{}{}", on, off)),
+        schedule(&"-".repeat(36)),
+    ]
+}
+
+fn hex_encode(value: &str) -> String {
+    value.bytes().map(|b| format!("{:02X}", b)).collect()
+}
+
+// Starts discovery and the watcher configured to find `simulator` on localhost.  `global` is
+// added to the top level of the configuration and `host` to the `[[host]]` table for the
+// simulator.
+async fn start_watcher(
+    simulator: &DaikinSimulator,
+    global: &str,
+    host: &str,
+) -> mpsc::Sender<anyhow::Error> {
+    let source = format!(
+        r#"
+        discover_bind_address = "127.0.0.1:0"
+        discover_addresses = ["{}"]
+        discover_interfaces = ["none"]
+        refresh_interval = 100
+        refresh_timeout = 500
+        device_identity = "mac"
+        {}
+
+        [[host]]
+        mac = "{}"
+        port = {}
+        {}
+        "#,
+        simulator.udp_address,
+        global,
+        simulator.unit.lock().unwrap().mac,
+        simulator.http_address.port(),
+        host,
+    );
+
+    let configuration: Configuration = toml::from_str(&source).unwrap();
+
+    let (error_tx, mut error_rx) = mpsc::channel(1);
+
+    let discover = DaikinDiscover::new(&configuration)
+        .await
+        .unwrap()
+        .start(error_tx.clone())
+        .await;
+
+    let mut watcher = DaikinWatcher::new(discover, &configuration).unwrap();
+    watcher.start().await;
+
+    tokio::spawn(async move {
+        if let Some(e) = error_rx.recv().await {
+            panic!("{:#}", e);
+        }
+    });
+
+    error_tx
+}
+
+// Waits up to five seconds for `condition`
+async fn eventually<F: Fn() -> bool>(condition: F) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(5);
+
+    while Instant::now() < deadline {
+        if condition() {
+            return true;
+        }
+
+        sleep(Duration::from_millis(50)).await;
+    }
+
+    false
+}
+
+async fn scrape(address: SocketAddr) -> String {
+    reqwest::get(format!("http://{}/metrics", address))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn end_to_end() {
+    let simulator = DaikinSimulator::start("0000000E2E01", "End to end").await;
+    let error_tx = start_watcher(&simulator, "", "").await;
+
+    // The metrics server registers its own metrics so only this test starts one
+    let metrics = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    };
+
+    DaikinExporter::new(metrics.to_string())
+        .unwrap()
+        .start(error_tx)
+        .await;
+
+    let device = [("device", "0000000E2E01"), ("name", "End to end")];
+
+    assert!(
+        eventually(|| metric_value("daikin_monitor_reset_count", &device).is_some()).await,
+        "unit was not discovered and polled"
+    );
+
+    assert_eq!(Some(1.0), metric_value("daikin_power_on", &device));
+    assert_eq!(
+        Some(22.5),
+        metric_value("daikin_set_temperature_degrees", &device)
+    );
+    assert_eq!(
+        Some(20.0),
+        metric_value("daikin_unit_temperature_degrees", &device)
+    );
+    assert_eq!(
+        Some(42.0),
+        metric_value("daikin_daily_runtime_minutes", &device)
+    );
+    assert_eq!(
+        Some(50.0),
+        metric_value("daikin_monitor_fan_speed_percent", &device)
+    );
+
+    let body = scrape(metrics).await;
+
+    assert!(body.contains(
+        r#"daikin_set_temperature_degrees{device="0000000E2E01",name="End to end"} 22.5"#
+    ));
+}
+
+#[tokio::test]
+async fn state_change() {
+    let simulator = DaikinSimulator::start("0000000E2E02", "State change").await;
+    start_watcher(&simulator, "", "").await;
+
+    let device = [("device", "0000000E2E02"), ("name", "State change")];
+
+    assert!(
+        eventually(|| metric_value("daikin_set_temperature_degrees", &device) == Some(22.5)).await
+    );
+
+    let url = format!(
+        "http://{}/aircon/set_control_info?pow=0&mode=3&stemp=19.0&shum=0&f_rate=5&f_dir=3",
+        simulator.http_address
+    );
+    let response = reqwest::get(url).await.unwrap().text().await.unwrap();
+    assert_eq!("ret=OK", response);
+
+    assert!(
+        eventually(|| metric_value("daikin_set_temperature_degrees", &device) == Some(19.0)).await
+    );
+    assert_eq!(Some(3.0), metric_value("daikin_mode", &device));
+    assert_eq!(Some(5.0), metric_value("daikin_fan_rate", &device));
+    assert_eq!(Some(3.0), metric_value("daikin_fan_direction", &device));
+}
+
+#[tokio::test]
+async fn malformed_field() {
+    let simulator = DaikinSimulator::start("0000000E2E03", "Malformed").await;
+    simulator.set_field("aircon/get_sensor_info", "htemp", "--");
+    start_watcher(&simulator, "", "").await;
+
+    let device = [("device", "0000000E2E03"), ("name", "Malformed")];
+
+    assert!(
+        eventually(|| metric_value("daikin_compressor_demand_percent", &device).is_some()).await
+    );
+
+    assert_eq!(
+        None,
+        metric_value("daikin_unit_temperature_degrees", &device)
+    );
+
+    // Polling continues after the malformed field
+    simulator.set_field("aircon/get_sensor_info", "cmpfreq", "12");
+
+    assert!(
+        eventually(|| metric_value("daikin_compressor_demand_percent", &device) == Some(12.0))
+            .await
+    );
+}
+
+#[tokio::test]
+async fn missing_field() {
+    let simulator = DaikinSimulator::start("0000000E2E21", "Missing").await;
+    start_watcher(&simulator, "", "").await;
+
+    let device = [("device", "0000000E2E21"), ("name", "Missing")];
+
+    assert!(
+        eventually(|| metric_value("daikin_daily_runtime_minutes", &device) == Some(42.0)).await
+    );
+
+    simulator.remove_field("aircon/get_week_power", "today_runtime");
+
+    assert!(eventually(|| metric_value("daikin_daily_runtime_minutes", &device).is_none()).await);
+
+    // An unsupported endpoint is skipped too
+    simulator.set_field("aircon/get_monitordata", "ret", "NOT SUPPORT");
+    simulator.set_field("aircon/get_monitordata", "ResetCount", "7");
+
+    // Polling continues after the missing key
+    simulator.set_field("aircon/get_sensor_info", "cmpfreq", "12");
+
+    assert!(
+        eventually(|| metric_value("daikin_compressor_demand_percent", &device) == Some(12.0))
+            .await
+    );
+    assert_eq!(
+        Some(3.0),
+        metric_value("daikin_monitor_reset_count", &device)
+    );
+}
+
+#[tokio::test]
+async fn rename() {
+    let simulator = DaikinSimulator::start("0000000E2E04", "Old name").await;
+    start_watcher(&simulator, "", "").await;
+
+    let old = [("device", "0000000E2E04"), ("name", "Old name")];
+    let new = [("device", "0000000E2E04"), ("name", "New name")];
+
+    assert!(eventually(|| metric_value("daikin_power_on", &old).is_some()).await);
+
+    simulator.set_name("New name");
+
+    assert!(eventually(|| metric_value("daikin_power_on", &new).is_some()).await);
+    assert_eq!(None, metric_value("daikin_power_on", &old));
+}
+
+#[tokio::test]
+async fn timeouts() {
+    let simulator = DaikinSimulator::start("0000000E2E05", "Slow").await;
+    simulator.set_latency(Duration::from_millis(50));
+    start_watcher(&simulator, "", "").await;
+
+    let device = [("device", "0000000E2E05"), ("name", "Slow")];
+
+    assert!(eventually(|| metric_value("daikin_power_on", &device).is_some()).await);
+
+    let host = simulator.http_address.ip().to_string();
+
+    // Request errors for this simulator, which is the only one on its address
+    let errors = || -> f64 {
+        prometheus::gather()
+            .into_iter()
+            .filter(|family| family.get_name() == "daikin_http_request_errors_total")
+            .flat_map(|family| family.get_metric().to_vec())
+            .filter(|metric| {
+                metric
+                    .get_label()
+                    .iter()
+                    .any(|label| label.get_name() == "host" && label.get_value() == host)
+            })
+            .map(|metric| metric.get_counter().get_value())
+            .sum()
+    };
+
+    let before = errors();
+
+    simulator.set_unresponsive(true);
+
+    assert!(
+        eventually(|| errors() > before).await,
+        "timeouts were not counted"
+    );
+}
+
+#[tokio::test]
+async fn registration() {
+    let simulator = DaikinSimulator::start_https("0000000E2E06", "Registered").await;
+    simulator.set_key("0123456789abcdef");
+    start_watcher(
+        &simulator,
+        "",
+        r#"
+        key = "0123456789abcdef"
+        uuid = "2b3c1e8a9f0d4c6e8b7a5d4c3b2a1f0e"
+        "#,
+    )
+    .await;
+
+    let device = [("device", "0000000E2E06"), ("name", "Registered")];
+
+    assert!(
+        eventually(|| metric_value("daikin_power_on", &device).is_some()).await,
+        "registration failed"
+    );
+
+    let host = simulator.http_address.ip().to_string();
+
+    let registrations = prometheus::gather()
+        .into_iter()
+        .find(|family| family.get_name() == "daikin_http_requests_total")
+        .unwrap()
+        .get_metric()
+        .iter()
+        .filter(|metric| {
+            metric.get_label().iter().any(|label| {
+                label.get_name() == "path" && label.get_value() == "common/register_terminal"
+            }) && metric
+                .get_label()
+                .iter()
+                .any(|label| label.get_name() == "host" && label.get_value() == host)
+        })
+        .map(|metric| metric.get_counter().get_value())
+        .sum::<f64>();
+
+    assert_eq!(1.0, registrations);
+
+    // The shared client still rejects the self-signed certificate
+    let url = format!("https://{}/common/basic_info", simulator.http_address);
+    assert!(daikin_adaptor::client().get(url).send().await.is_err());
+}
+
+#[tokio::test]
+async fn registration_by_mac() {
+    let simulator = DaikinSimulator::start_https("0000000E2E22", "Configured").await;
+    simulator.set_key("0123456789abcdef");
+
+    // The configured address has no key, it is only found in the [[host]] matching the MAC
+    // learned from discovery
+    let address = format!(
+        "[[host]]\naddress = \"{}\"\nport = {}",
+        simulator.http_address.ip(),
+        simulator.http_address.port()
+    );
+
+    start_watcher(
+        &simulator,
+        &address,
+        r#"
+        key = "0123456789abcdef"
+        uuid = "2b3c1e8a9f0d4c6e8b7a5d4c3b2a1f0e"
+        "#,
+    )
+    .await;
+
+    let device = [("device", "0000000E2E22"), ("name", "Configured")];
+
+    assert!(
+        eventually(|| metric_value("daikin_power_on", &device).is_some()).await,
+        "registration failed"
+    );
+}
+
+#[tokio::test]
+async fn discover_address_unreachable() {
+    let simulator = DaikinSimulator::start("0000000E2E27", "Unreachable").await;
+
+    // Sending to port 0 fails, which must not stop requests to the other addresses
+    let source = format!(
+        r#"
+        discover_bind_address = "127.0.0.1:0"
+        discover_addresses = ["127.0.0.1:0", "{}"]
+        discover_interfaces = ["none"]
+        "#,
+        simulator.udp_address
+    );
+
+    let configuration: Configuration = toml::from_str(&source).unwrap();
+
+    let (error_tx, mut error_rx) = mpsc::channel(1);
+
+    let discover = DaikinDiscover::new(&configuration)
+        .await
+        .unwrap()
+        .start(error_tx)
+        .await;
+
+    let mut discovered = discover.subscribe();
+
+    let unit = tokio::time::timeout(Duration::from_secs(5), discovered.recv())
+        .await
+        .expect("unit was not discovered")
+        .unwrap();
+
+    assert_eq!(Some("0000000E2E27"), unit.mac.as_deref());
+    assert!(error_rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn restored_unit_denied() {
+    let simulator = DaikinSimulator::start("0000000E2E23", "Denied").await;
+
+    let path =
+        std::env::temp_dir().join(format!("daikin_state_denied_{}.toml", std::process::id()));
+    let last_seen = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    std::fs::write(
+        &path,
+        format!(
+            "[[unit]]\nmac = \"0000000E2E23\"\naddress = \"{}\"\nlast_seen = {}\n",
+            simulator.http_address.ip(),
+            last_seen
+        ),
+    )
+    .unwrap();
+
+    // A unit recorded before a deny rule was added is forgotten rather than polled
+    start_watcher(
+        &simulator,
+        &format!(
+            r#"state_file = "{}"
+
+            [[discover_deny]]
+            mac = "0000000E2E23""#,
+            path.display()
+        ),
+        "",
+    )
+    .await;
+
+    let forgotten = || {
+        !std::fs::read_to_string(&path)
+            .unwrap()
+            .contains("0000000E2E23")
+    };
+
+    assert!(eventually(forgotten).await);
+
+    sleep(Duration::from_millis(500)).await;
+
+    assert_eq!(
+        None,
+        metric_value("daikin_power_on", &[("device", "0000000E2E23")])
+    );
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn airbase() {
+    let simulator = DaikinSimulator::start("0000000E2E07", "Ducted").await;
+    simulator.set_airbase();
+    start_watcher(&simulator, "raw_metrics = true", "").await;
+
+    let device = [("device", "0000000E2E07"), ("name", "Ducted")];
+
+    assert!(
+        eventually(|| metric_value("daikin_set_temperature_degrees", &device).is_some()).await,
+        "AirBase adaptor was not detected"
+    );
+
+    // basic_info is recorded under the path it was read from
+    let raw = |endpoint| {
+        metric_value(
+            "daikin_raw_value",
+            &[
+                ("device", "0000000E2E07"),
+                ("endpoint", endpoint),
+                ("key", "adp_kind"),
+            ],
+        )
+    };
+
+    assert_eq!(Some(2.0), raw("skyfi/common/basic_info"));
+    assert_eq!(None, raw("common/basic_info"));
+
+    // AirBase cool and mid fan are translated to the BRP072A values
+    assert_eq!(Some(3.0), metric_value("daikin_mode", &device));
+    assert_eq!(Some(5.0), metric_value("daikin_fan_rate", &device));
+
+    let zone = |name| {
+        metric_value(
+            "daikin_zone_on",
+            &[("device", "0000000E2E07"), ("zone", name)],
+        )
+    };
+
+    assert!(eventually(|| zone("Lounge").is_some()).await);
+    assert_eq!(Some(1.0), zone("Lounge"));
+    assert_eq!(Some(0.0), zone("Bed 2"));
+
+    simulator.set_field(
+        "skyfi/aircon/get_zone_setting",
+        "zone_name",
+        &percent_encode("Lounge;Bedroom 2;Zone 3"),
+    );
+
+    assert!(eventually(|| zone("Bedroom 2").is_some()).await);
+    assert_eq!(None, zone("Bed 2"));
+}
+
+#[tokio::test]
+async fn zones() {
+    let simulator = DaikinSimulator::start("0000000E2E08", "Zoned").await;
+    start_watcher(&simulator, "", "").await;
+
+    let zone = |name| {
+        metric_value(
+            "daikin_zone_on",
+            &[("device", "0000000E2E08"), ("zone", name)],
+        )
+    };
+
+    assert!(
+        eventually(|| zone("Bed 2") == Some(0.0)).await,
+        "zones were not polled for en_setzone=1"
+    );
+
+    let args: Vec<String> = vec![
+        "zone".into(),
+        simulator.http_address.to_string(),
+        "Bed 2".into(),
+        "on".into(),
+    ];
+
+    daikin_control::run(Configuration::default(), &args)
+        .await
+        .unwrap();
+
+    assert!(eventually(|| zone("Bed 2") == Some(1.0)).await);
+    assert_eq!(Some(1.0), zone("Lounge"));
+    assert_eq!(Some(0.0), zone("Zone 3"));
+
+    let args: Vec<String> = vec![
+        "zone".into(),
+        simulator.http_address.to_string(),
+        "Attic".into(),
+        "on".into(),
+    ];
+
+    assert!(daikin_control::run(Configuration::default(), &args)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn schedule_unsupported() {
+    let simulator = DaikinSimulator::start("0000000E2E28", "Unscheduled").await;
+    simulator.set_field("aircon/get_model_info", "en_scdltmr", "0");
+    start_watcher(&simulator, "", "").await;
+
+    let host = simulator.http_address.ip().to_string();
+    let requests = |path| {
+        metric_value(
+            "daikin_http_requests_total",
+            &[("host", &host), ("path", path)],
+        )
+    };
+
+    assert!(eventually(|| requests("aircon/get_sensor_info") >= Some(3.0)).await);
+
+    assert_eq!(Some(1.0), requests("aircon/get_model_info"));
+    assert_eq!(None, requests("aircon/get_scdltimer_info"));
+    assert_eq!(
+        None,
+        metric_value("daikin_schedule_enabled", &[("device", "0000000E2E28")])
+    );
+}
+
+#[tokio::test]
+async fn schedule() {
+    let simulator = DaikinSimulator::start("0000000E2E09", "Scheduled").await;
+    start_watcher(&simulator, "", "").await;
+
+    let device = [("device", "0000000E2E09"), ("name", "Scheduled")];
+
+    assert!(
+        eventually(|| {
+            metric_value("daikin_schedule_next_action_timestamp_seconds", &device).is_some()
+        })
+        .await,
+        "schedule was not read"
+    );
+
+    assert_eq!(Some(1.0), metric_value("daikin_schedule_enabled", &device));
+    assert_eq!(
+        Some(4.0),
+        metric_value("daikin_schedule_next_mode", &device)
+    );
+
+    let now = Local::now();
+    let today = now.date_naive();
+    let seven = NaiveTime::from_hms_opt(7, 0, 0).unwrap();
+    let ten = NaiveTime::from_hms_opt(22, 0, 0).unwrap();
+
+    let (at, power_on) = if now.time() < seven {
+        (today.and_time(seven), 1.0)
+    } else if now.time() < ten {
+        (today.and_time(ten), 0.0)
+    } else {
+        (today.succ_opt().unwrap().and_time(seven), 1.0)
+    };
+
+    let at = Local.from_local_datetime(&at).earliest().unwrap();
+
+    assert_eq!(
+        Some(at.timestamp() as f64),
+        metric_value("daikin_schedule_next_action_timestamp_seconds", &device)
+    );
+    assert_eq!(
+        Some(power_on),
+        metric_value("daikin_schedule_next_power_on", &device)
+    );
+}
+
+#[tokio::test]
+async fn schedule_backup_restore() {
+    let original = DaikinSimulator::start("0000000E2E10", "Original").await;
+    let replacement = DaikinSimulator::start("0000000E2E11", "Replacement").await;
+    replacement.clear_schedules();
+
+    let file = std::env::temp_dir().join(format!("daikin-schedule-{}.toml", std::process::id()));
+    let file = file.to_str().unwrap().to_string();
+
+    let args = |command: &str, simulator: &DaikinSimulator| -> Vec<String> {
+        vec![
+            command.to_string(),
+            simulator.http_address.to_string(),
+            file.clone(),
+        ]
+    };
+
+    daikin_control::run(
+        Configuration::default(),
+        &args("schedule-backup", &original),
+    )
+    .await
+    .unwrap();
+
+    let backup: ScheduleBackup = toml::from_str(&std::fs::read_to_string(&file).unwrap()).unwrap();
+    assert_eq!(2, backup.schedules.len());
+    assert_eq!(Some("1"), backup.info.get("active_no").map(|a| a.as_str()));
+
+    assert_ne!(original.schedules(), replacement.schedules());
+
+    daikin_control::run(
+        Configuration::default(),
+        &args("schedule-restore", &replacement),
+    )
+    .await
+    .unwrap();
+
+    std::fs::remove_file(&file).unwrap();
+
+    // The restore request includes the target which the adaptor echoes
+    let restored: Vec<BTreeMap<String, String>> = replacement
+        .schedules()
+        .into_iter()
+        .map(|mut schedule| {
+            schedule.remove("target");
+            schedule
+        })
+        .collect();
+
+    assert_eq!(original.schedules(), restored);
+}
+
+#[tokio::test]
+async fn clock_skew() {
+    let simulator = DaikinSimulator::start("0000000E2E12", "Drifting").await;
+    simulator.set_clock_offset(chrono::Duration::seconds(3600));
+    start_watcher(&simulator, "clock_check_interval = 100", "").await;
+
+    let device = [("device", "0000000E2E12"), ("name", "Drifting")];
+
+    assert!(eventually(|| metric_value("daikin_clock_skew_seconds", &device).is_some()).await);
+
+    let skew = metric_value("daikin_clock_skew_seconds", &device).unwrap();
+    assert!((skew - 3600.0).abs() < 2.0, "skew {}", skew);
+
+    // The clock is only set when clock_sync is enabled
+    assert_eq!(3600, simulator.clock_offset().num_seconds());
+}
+
+#[tokio::test]
+async fn clock_sync() {
+    let simulator = DaikinSimulator::start("0000000E2E13", "Synced").await;
+    simulator.set_clock_offset(chrono::Duration::seconds(-600));
+    start_watcher(
+        &simulator,
+        "clock_check_interval = 100\nclock_sync = true",
+        "",
+    )
+    .await;
+
+    let device = [("device", "0000000E2E13"), ("name", "Synced")];
+
+    assert!(
+        eventually(|| simulator.clock_offset().num_seconds().abs() < 2).await,
+        "clock was not set"
+    );
+
+    assert!(
+        eventually(|| {
+            metric_value("daikin_clock_skew_seconds", &device).is_some_and(|skew| skew.abs() < 2.0)
+        })
+        .await
+    );
+}
+
+#[tokio::test]
+async fn clock_sync_stops() {
+    let zone = DaikinSimulator::start("0000000E2E25", "Other zone").await;
+    zone.set_clock_offset(chrono::Duration::seconds(7200));
+    start_watcher(&zone, "clock_check_interval = 100\nclock_sync = true", "").await;
+
+    let locked = DaikinSimulator::start("0000000E2E26", "Locked").await;
+    locked.set_clock_offset(chrono::Duration::seconds(-600));
+    locked.set_clock_locked(true);
+    start_watcher(&locked, "clock_check_interval = 100\nclock_sync = true", "").await;
+
+    let clock_sets = |simulator: &DaikinSimulator| {
+        let host = simulator.http_address.ip().to_string();
+
+        metric_value(
+            "daikin_http_requests_total",
+            &[("host", &host), ("path", "common/notify_date_time")],
+        )
+    };
+
+    // A clock that doesn't change after being set is set once, one whole hours off never
+    assert!(eventually(|| clock_sets(&locked) == Some(1.0)).await);
+
+    sleep(Duration::from_millis(1000)).await;
+
+    assert_eq!(Some(1.0), clock_sets(&locked));
+    assert_eq!(None, clock_sets(&zone));
+    assert_eq!(7200, zone.clock_offset().num_seconds());
+    assert!(
+        metric_value("daikin_clock_skew_seconds", &[("device", "0000000E2E25")])
+            .is_some_and(|skew| (skew - 7200.0).abs() < 2.0)
+    );
+}
+
+#[tokio::test]
+async fn firmware() {
+    let simulator = DaikinSimulator::start("0000000E2E14", "Firmware").await;
+    start_watcher(
+        &simulator,
+        "[[known_firmware]]\nversion = \"2_9_0\"\nchecksum = \"36085\"",
+        "",
+    )
+    .await;
+
+    let device = [("device", "0000000E2E14"), ("name", "Firmware")];
+    let build = |version| {
+        metric_value(
+            "daikin_firmware_info",
+            &[("device", "0000000E2E14"), ("version", version)],
+        )
+    };
+
+    assert!(eventually(|| build("2_9_0").is_some()).await);
+
+    assert_eq!(
+        Some(1.0),
+        metric_value(
+            "daikin_firmware_info",
+            &[("device", "0000000E2E14"), ("checksum", "36085")]
+        )
+    );
+    assert_eq!(
+        Some(1.0),
+        metric_value("daikin_firmware_known_good", &device)
+    );
+
+    simulator.set_field("common/basic_info", "ver", "3_0_0");
+
+    assert!(eventually(|| build("3_0_0").is_some()).await);
+    assert_eq!(None, build("2_9_0"));
+    assert_eq!(
+        Some(0.0),
+        metric_value("daikin_firmware_known_good", &device)
+    );
+}
+
+#[tokio::test]
+async fn firmware_checksum_retry() {
+    let simulator = DaikinSimulator::start("0000000E2E24", "Checksum").await;
+    simulator.set_field("common/get_progsum", "ret", "PARAM NG");
+
+    start_watcher(
+        &simulator,
+        "[[known_firmware]]\nversion = \"2_9_0\"\nchecksum = \"36085\"",
+        "",
+    )
+    .await;
+
+    let device = [("device", "0000000E2E24"), ("name", "Checksum")];
+    let checksum = |checksum| {
+        metric_value(
+            "daikin_firmware_info",
+            &[("device", "0000000E2E24"), ("checksum", checksum)],
+        )
+    };
+
+    // Without a checksum the build can't be judged
+    assert!(eventually(|| checksum("").is_some()).await);
+    assert_eq!(None, metric_value("daikin_firmware_known_good", &device));
+
+    simulator.set_field("common/get_progsum", "ret", "OK");
+
+    assert!(eventually(|| checksum("36085").is_some()).await);
+    assert_eq!(None, checksum(""));
+    assert_eq!(
+        Some(1.0),
+        metric_value("daikin_firmware_known_good", &device)
+    );
+}
+
+#[tokio::test]
+async fn hvac_action() {
+    let simulator = DaikinSimulator::start("0000000E2E15", "Action").await;
+    start_watcher(&simulator, "", "").await;
+
+    let device = [("device", "0000000E2E15"), ("name", "Action")];
+    let action = |action| {
+        metric_value(
+            "daikin_hvac_action",
+            &[("device", "0000000E2E15"), ("action", action)],
+        )
+    };
+    let seconds = |state| {
+        metric_value(
+            "daikin_state_seconds_total",
+            &[("device", "0000000E2E15"), ("state", state)],
+        )
+        .unwrap_or_default()
+    };
+
+    assert!(eventually(|| action("heating") == Some(1.0)).await);
+    assert_eq!(Some(0.0), action("idle"));
+    assert_eq!(
+        Some(2.5),
+        metric_value("daikin_temperature_delta_degrees", &device)
+    );
+    assert!(eventually(|| seconds("heating") > 0.0).await);
+
+    simulator.set_field("aircon/get_sensor_info", "cmpfreq", "0");
+
+    assert!(eventually(|| action("idle") == Some(1.0)).await);
+    assert_eq!(Some(0.0), action("heating"));
+    assert!(eventually(|| seconds("idle") > 0.0).await);
+
+    let heating = seconds("heating");
+
+    let url = format!(
+        "http://{}/aircon/set_control_info?pow=0&mode=4&stemp=22.5&shum=0&f_rate=A&f_dir=0",
+        simulator.http_address
+    );
+    reqwest::get(url).await.unwrap();
+
+    assert!(eventually(|| action("off") == Some(1.0)).await);
+    assert_eq!(heating, seconds("heating"));
+}
+
+#[tokio::test]
+async fn estimated_power() {
+    let simulator = DaikinSimulator::start("0000000E2E16", "Power").await;
+    start_watcher(
+        &simulator,
+        "[[power_model]]\nname = \"FTXM35\"\nrated_watts = 1200\nidle_watts = 20\ncurve = [[20, 300], [60, 900]]",
+        "power_model = \"FTXM35\"",
+    )
+    .await;
+
+    let device = [("device", "0000000E2E16"), ("name", "Power")];
+    let watts = || metric_value("daikin_estimated_power_watts", &device);
+    let joules =
+        || metric_value("daikin_estimated_energy_joules_total", &device).unwrap_or_default();
+
+    // cmpfreq 38 is between the 20 and 60 curve points
+    assert!(eventually(|| watts() == Some(570.0)).await);
+    assert!(eventually(|| joules() > 0.0).await);
+
+    simulator.set_field("aircon/get_sensor_info", "cmpfreq", "0");
+
+    assert!(eventually(|| watts() == Some(20.0)).await);
+
+    simulator.set_field("aircon/get_sensor_info", "cmpfreq", "120");
+
+    assert!(eventually(|| watts() == Some(900.0)).await);
+
+    simulator.set_field("aircon/get_control_info", "pow", "0");
+    simulator.set_field("common/basic_info", "pow", "0");
+
+    assert!(eventually(|| watts() == Some(0.0)).await);
+}
+
+#[tokio::test]
+async fn enum_state_sets() {
+    let simulator = DaikinSimulator::start("0000000E2E17", "State sets").await;
+    simulator.set_field("aircon/get_control_info", "f_rate", "Z");
+    start_watcher(&simulator, "enum_metrics = \"stateset\"", "").await;
+
+    let device = [("device", "0000000E2E17"), ("name", "State sets")];
+    let mode = |mode| {
+        metric_value(
+            "daikin_mode_state",
+            &[("device", "0000000E2E17"), ("mode", mode)],
+        )
+    };
+    let fan_rate = |rate| {
+        metric_value(
+            "daikin_fan_rate_state",
+            &[("device", "0000000E2E17"), ("fan_rate", rate)],
+        )
+    };
+
+    assert!(eventually(|| mode("heat") == Some(1.0)).await);
+    assert_eq!(Some(0.0), mode("auto"));
+    assert_eq!(None, metric_value("daikin_mode", &device));
+
+    // The malformed fan rate is skipped without stopping polling
+    assert_eq!(None, fan_rate("auto"));
+
+    simulator.set_field("aircon/get_control_info", "f_rate", "B");
+
+    assert!(eventually(|| fan_rate("quiet") == Some(1.0)).await);
+    assert_eq!(Some(0.0), fan_rate("level_3"));
+    assert_eq!(None, metric_value("daikin_fan_rate", &device));
+}
+
+#[tokio::test]
+async fn monitor_data() {
+    let simulator = DaikinSimulator::start("0000000E2E18", "Monitor").await;
+    simulator.set_field("aircon/get_monitordata", "fangl", "zz");
+    start_watcher(&simulator, "", "").await;
+
+    let device = [("device", "0000000E2E18"), ("name", "Monitor")];
+
+    assert!(
+        eventually(
+            || metric_value("daikin_monitor_heat_exchanger_temperature_degrees", &device)
+                == Some(30.5)
+        )
+        .await
+    );
+    assert_eq!(
+        Some(21.5),
+        metric_value("daikin_monitor_rawr_temperature_degrees", &device)
+    );
+    assert_eq!(
+        Some(20.0),
+        metric_value("daikin_monitor_tr_temperature_degrees", &device)
+    );
+    assert_eq!(Some(1.0), metric_value("daikin_monitor_power_on", &device));
+    assert_eq!(Some(4.0), metric_value("daikin_monitor_mode", &device));
+    assert_eq!(
+        Some(38.0),
+        metric_value(
+            "daikin_monitor_raw",
+            &[("device", "0000000E2E18"), ("key", "dmnd")]
+        )
+    );
+    assert_eq!(
+        Some(0.0),
+        metric_value(
+            "daikin_monitor_raw",
+            &[("device", "0000000E2E18"), ("key", "tap")]
+        )
+    );
+
+    // The field that isn't hex-encoded is skipped
+    assert_eq!(None, metric_value("daikin_monitor_fangl", &device));
+    assert_eq!(
+        None,
+        metric_value(
+            "daikin_monitor_raw",
+            &[("device", "0000000E2E18"), ("key", "ResetCount")]
+        )
+    );
+}
+
+#[tokio::test]
+async fn raw_metrics() {
+    let simulator = DaikinSimulator::start("0000000E2E19", "Raw").await;
+    start_watcher(&simulator, "raw_metrics = true", "").await;
+
+    let raw = |endpoint, key| {
+        metric_value(
+            "daikin_raw_value",
+            &[
+                ("device", "0000000E2E19"),
+                ("endpoint", endpoint),
+                ("key", key),
+            ],
+        )
+    };
+    let info = |key, value| {
+        metric_value(
+            "daikin_raw_info",
+            &[
+                ("device", "0000000E2E19"),
+                ("endpoint", "common/basic_info"),
+                ("key", key),
+                ("value", value),
+            ],
+        )
+    };
+
+    assert!(eventually(|| raw("aircon/get_sensor_info", "cmpfreq") == Some(38.0)).await);
+    assert_eq!(Some(2.0), raw("common/basic_info", "adp_kind"));
+    assert_eq!(Some(1.0), raw("aircon/get_scdltimer_info", "en_scdltimer"));
+    assert_eq!(None, raw("aircon/get_sensor_info", "ret"));
+
+    // Credentials are never exported
+    simulator.set_field("common/basic_info", "id", "drbrain");
+    simulator.set_field("common/basic_info", "pw", "0c36350a");
+    simulator.set_field("common/basic_info", "grp_name", "Upstairs");
+
+    assert!(eventually(|| info("grp_name", "Upstairs") == Some(1.0)).await);
+
+    for key in ["id", "pw", "lpw"] {
+        let series = [("device", "0000000E2E19"), ("key", key)];
+
+        assert_eq!(None, metric_value("daikin_raw_info", &series), "{}", key);
+        assert_eq!(None, metric_value("daikin_raw_value", &series), "{}", key);
+    }
+    assert_eq!(Some(1.0), info("adp_mode", "run"));
+
+    // Responses read outside the endpoint polling
+    assert!(eventually(|| raw("common/get_progsum", "csum") == Some(36085.0)).await);
+    assert!(eventually(|| raw("common/get_datetime", "sta") == Some(2.0)).await);
+    assert!(eventually(|| raw("aircon/get_scdltimer_body", "target") == Some(1.0)).await);
+
+    simulator.set_field("common/basic_info", "adp_mode", "ota");
+
+    assert!(eventually(|| info("adp_mode", "ota") == Some(1.0)).await);
+    assert_eq!(None, info("adp_mode", "run"));
+
+    // A key that stops being numeric moves to daikin_raw_info and back
+    simulator.set_field("common/basic_info", "adp_kind", "-");
+
+    assert!(eventually(|| info("adp_kind", "-") == Some(1.0)).await);
+    assert_eq!(None, raw("common/basic_info", "adp_kind"));
+
+    simulator.set_field("common/basic_info", "adp_kind", "3");
+
+    assert!(eventually(|| raw("common/basic_info", "adp_kind") == Some(3.0)).await);
+    assert_eq!(None, info("adp_kind", "-"));
+}
+
+#[tokio::test]
+async fn custom_metrics() {
+    let simulator = DaikinSimulator::start("0000000E2E20", "Custom").await;
+    start_watcher(
+        &simulator,
+        r#"
+        [[metric]]
+        endpoint = "aircon/get_sensor_info"
+        key = "cmpfreq"
+        name = "daikin_test_compressor_hertz"
+        help = "Compressor frequency"
+        scale = 0.5
+
+        [[metric]]
+        endpoint = "common/basic_info"
+        key = "adp_mode"
+        name = "daikin_test_adaptor_running"
+        values = { run = 1, ota = 0 }
+
+        [[metric]]
+        endpoint = "common/basic_info"
+        key = "name"
+        name = "daikin_test_name_length"
+        decode = "percent"
+        values = { Custom = 6 }
+
+        [[metric]]
+        endpoint = "aircon/get_monitordata"
+        key = "fan"
+        name = "daikin_test_fan"
+        decode = "hex-ascii"
+
+        [[metric]]
+        endpoint = "aircon/get_monitordata"
+        key = "ResetCount"
+        name = "daikin_test_resets_total"
+        type = "counter"
+        "#,
+        // The endpoints of [[metric]] entries are polled too
+        r#"endpoints = ["aircon/get_control_info"]"#,
+    )
+    .await;
+
+    let device = [("device", "0000000E2E20"), ("name", "Custom")];
+
+    assert!(
+        eventually(|| metric_value("daikin_test_compressor_hertz", &device) == Some(19.0)).await
+    );
+    assert_eq!(
+        Some(1.0),
+        metric_value("daikin_test_adaptor_running", &device)
+    );
+    assert_eq!(Some(6.0), metric_value("daikin_test_name_length", &device));
+    assert_eq!(Some(50.0), metric_value("daikin_test_fan", &device));
+    assert_eq!(Some(3.0), metric_value("daikin_test_resets_total", &device));
+
+    simulator.set_field("common/basic_info", "adp_mode", "ota");
+
+    assert!(eventually(|| metric_value("daikin_test_adaptor_running", &device) == Some(0.0)).await);
+
+    // Values missing from the enum map are skipped
+    simulator.set_field("common/basic_info", "adp_mode", "unknown");
+    simulator.set_field("aircon/get_sensor_info", "cmpfreq", "40");
+
+    assert!(
+        eventually(|| metric_value("daikin_test_compressor_hertz", &device) == Some(20.0)).await
+    );
+    assert_eq!(
+        Some(0.0),
+        metric_value("daikin_test_adaptor_running", &device)
+    );
+}
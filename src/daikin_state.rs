@@ -0,0 +1,241 @@
+use anyhow::Context;
+use anyhow::Result;
+
+use crate::daikin_discover::DiscoveredUnit;
+
+use log::debug;
+use log::error;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use tokio::sync::Notify;
+
+// Minimum time between writes when only the last seen time of a unit changed
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+// A discovered unit recorded in the state file
+#[derive(Clone, Deserialize, Serialize)]
+pub struct StateUnit {
+    pub mac: String,
+    pub address: String,
+    pub name: Option<String>,
+    // Seconds since the unix epoch
+    pub last_seen: u64,
+}
+
+impl StateUnit {
+    // The unit as discovery would report it, for checking it against the discover rules
+    pub fn discovered(&self) -> DiscoveredUnit {
+        DiscoveredUnit {
+            address: self.address.clone(),
+            mac: Some(self.mac.clone()),
+            name: self.name.clone(),
+            version: None,
+            adaptor_type: None,
+            port: None,
+            interface: None,
+        }
+    }
+
+    // Time since discovery last saw the unit
+    pub fn age(&self) -> Duration {
+        let last_seen = UNIX_EPOCH + Duration::from_secs(self.last_seen);
+
+        SystemTime::now()
+            .duration_since(last_seen)
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct StateFile {
+    #[serde(default, rename = "unit")]
+    units: Vec<StateUnit>,
+}
+
+// Discovered units persisted across restarts so they can be polled before discovery finds them
+// again.  Changes are written by write_loop so callers never wait on the file.
+
+pub struct DaikinState {
+    path: PathBuf,
+    units: Mutex<BTreeMap<String, StateUnit>>,
+    // Notified when the units change.  Changes made during a write are coalesced into one more.
+    changed: Notify,
+}
+
+impl DaikinState {
+    // Loads the state file at `path`.  A missing file is an empty state.
+    pub fn load(path: &str) -> Result<Self> {
+        let path = PathBuf::from(path);
+
+        let state: StateFile = if path.exists() {
+            let source = fs::read_to_string(&path)
+                .with_context(|| format!("Unable to read state file {}", path.display()))?;
+
+            toml::from_str(&source)
+                .with_context(|| format!("Unable to parse state file {}", path.display()))?
+        } else {
+            StateFile::default()
+        };
+
+        let units = state
+            .units
+            .into_iter()
+            .map(|unit| (unit.mac.clone(), unit))
+            .collect();
+
+        Ok(DaikinState {
+            path,
+            units: Mutex::new(units),
+            changed: Notify::new(),
+        })
+    }
+
+    pub fn units(&self) -> Vec<StateUnit> {
+        self.units.lock().unwrap().values().cloned().collect()
+    }
+
+    // Records a discovered unit, writing the state file if the unit is new, has moved, or was
+    // last written a while ago
+    pub fn seen(&self, unit: &DiscoveredUnit) {
+        let mac = match &unit.mac {
+            Some(m) => m,
+            None => return,
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        {
+            let mut units = self.units.lock().unwrap();
+
+            let changed = match units.get(mac) {
+                Some(old) => {
+                    old.address != unit.address
+                        || old.name != unit.name
+                        || now.saturating_sub(old.last_seen) >= REFRESH_INTERVAL.as_secs()
+                }
+                None => true,
+            };
+
+            if !changed {
+                return;
+            }
+
+            let state_unit = StateUnit {
+                mac: mac.clone(),
+                address: unit.address.clone(),
+                name: unit.name.clone(),
+                last_seen: now,
+            };
+
+            units.insert(mac.clone(), state_unit);
+        }
+
+        self.changed.notify_one();
+    }
+
+    // Forgets a unit that is no longer polled
+    pub fn remove(&self, mac: &str) {
+        let removed = self.units.lock().unwrap().remove(mac).is_some();
+
+        if removed {
+            self.changed.notify_one();
+        }
+    }
+
+    // Writes the state file whenever the units change
+    pub async fn write_loop(&self) {
+        debug!("Starting state file write loop");
+
+        loop {
+            self.changed.notified().await;
+
+            self.write().await;
+        }
+    }
+
+    async fn write(&self) {
+        if let Err(e) = self.try_write().await {
+            error!("{:#}", e);
+        }
+    }
+
+    // Writes a snapshot of the units to a temporary file then renames it so a crash never leaves a
+    // partial state file.  The units aren't locked while writing.
+    async fn try_write(&self) -> Result<()> {
+        let (source, count) = {
+            let units = self.units.lock().unwrap();
+
+            let state = StateFile {
+                units: units.values().cloned().collect(),
+            };
+
+            let source = toml::to_string(&state).context("Unable to serialize state")?;
+
+            (source, units.len())
+        };
+
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+
+        tokio::fs::write(&temporary, source)
+            .await
+            .with_context(|| format!("Unable to write state file {}", self.path.display()))?;
+
+        tokio::fs::rename(&temporary, &self.path)
+            .await
+            .with_context(|| format!("Unable to write state file {}", self.path.display()))?;
+
+        debug!("Wrote {} units to {}", count, self.path.display());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn seen_and_remove() {
+        let path = std::env::temp_dir().join(format!("daikin_state_{}.toml", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let state = DaikinState::load(path).unwrap();
+        assert!(state.units().is_empty());
+
+        let unit = DiscoveredUnit::parse(
+            "10.101.28.64".to_string(),
+            "ret=OK,name=%42%65%64%72%6f%6f%6d,mac=60F189B4B2D0",
+        );
+
+        state.seen(&unit);
+        state.write().await;
+
+        let units = DaikinState::load(path).unwrap().units();
+        assert_eq!(1, units.len());
+        assert_eq!("60F189B4B2D0", units[0].mac);
+        assert_eq!("10.101.28.64", units[0].address);
+        assert_eq!(Some("Bedroom"), units[0].name.as_deref());
+
+        state.remove("60F189B4B2D0");
+        state.write().await;
+
+        assert!(DaikinState::load(path).unwrap().units().is_empty());
+
+        fs::remove_file(path).unwrap();
+    }
+}
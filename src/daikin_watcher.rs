@@ -1,58 +1,163 @@
+use crate::configuration::normalize_mac;
 use crate::configuration::Configuration;
+use crate::custom_metrics::CustomMetrics;
+use crate::daikin_adaptor;
 use crate::daikin_adaptor::DaikinAdaptor;
+use crate::daikin_discover::DiscoverHandle;
+use crate::daikin_discover::DiscoveredUnit;
+use crate::daikin_state::DaikinState;
+use crate::discover_filter::DiscoverFilter;
 
+use anyhow::Result;
+
+use lazy_static::lazy_static;
+
+use log::debug;
 use log::info;
+use log::warn;
+
+use prometheus::register_int_counter;
+use prometheus::GaugeVec;
+use prometheus::IntCounter;
 
 use reqwest::Client;
 
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::sync::Arc;
 use std::time::Duration;
+use std::time::Instant;
 
-use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time::interval;
+use tokio::time::MissedTickBehavior;
+
+// Keyed by MAC address, or by host for configured hosts whose MAC isn't known yet
+type Adaptors = HashMap<String, Watched>;
+
+lazy_static! {
+    static ref REMOVED: IntCounter = register_int_counter!(
+        "daikin_adaptors_removed_total",
+        "Number of Daikin adaptors removed after disappearing from the network"
+    )
+    .unwrap();
+}
 
-type Adaptors = HashMap<String, DaikinAdaptor>;
-type AddressSender = broadcast::Sender<String>;
+struct Watched {
+    adaptor: DaikinAdaptor,
+    task: JoinHandle<()>,
+
+    // Last time discovery saw the unit.  None for configured hosts, which are never removed.
+    last_seen: Option<Instant>,
+}
+
+impl Watched {
+    // Unseen by discovery and failing polls for longer than `expiry`
+    fn expired(&self, expiry: Duration) -> bool {
+        let last_seen = match self.last_seen {
+            Some(l) => l,
+            None => return false,
+        };
+
+        last_seen.elapsed() > expiry && self.adaptor.last_success().elapsed() > expiry
+    }
+
+    fn seen(&mut self) {
+        if self.last_seen.is_some() {
+            self.last_seen = Some(Instant::now());
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct DaikinWatcher {
     adaptors: Arc<Mutex<Adaptors>>,
-    discover: AddressSender,
+    discover: DiscoverHandle,
     client: Client,
+    configuration: Arc<Configuration>,
+    expiry: Duration,
+    filter: Arc<DiscoverFilter>,
     hosts: Option<Vec<String>>,
-    interval: Duration,
+    labels: Option<GaugeVec>,
+    custom_metrics: Option<CustomMetrics>,
+    state: Option<Arc<DaikinState>>,
 }
 
 impl DaikinWatcher {
-    pub fn new(discover: AddressSender, configuration: &Configuration) -> Self {
+    pub fn new(discover: DiscoverHandle, configuration: &Configuration) -> Result<Self> {
+        let expiry = configuration.adaptor_expiry();
+        let filter = Arc::new(DiscoverFilter::new(configuration)?);
         let hosts = configuration.hosts();
-        let interval = configuration.refresh_interval();
-        let timeout = configuration.refresh_timeout();
+        let labels = daikin_adaptor::register_labels(&configuration.host_label_names());
+        let custom_metrics = CustomMetrics::register(configuration.metrics())?;
 
-        let client = Client::builder()
-            .connect_timeout(timeout)
-            .http1_only()
-            .timeout(timeout)
-            .build()
-            .expect("Could not build client");
+        let state = match configuration.state_file() {
+            Some(path) => Some(Arc::new(DaikinState::load(&path)?)),
+            None => None,
+        };
+
+        let configuration = Arc::new(configuration.clone());
+
+        let client = daikin_adaptor::client();
 
         let adaptors = Arc::new(Mutex::new(HashMap::new()));
 
-        DaikinWatcher {
+        Ok(DaikinWatcher {
             adaptors,
             discover,
             client,
+            configuration,
+            expiry,
+            filter,
             hosts,
-            interval,
-        }
+            labels,
+            custom_metrics,
+            state,
+        })
     }
 
     pub async fn start(&mut self) {
         if let Some(hosts) = self.hosts.clone() {
             for host in hosts {
-                self.start_adaptor(&host).await;
+                self.start_adaptor(&host, None, None).await;
+            }
+        }
+
+        if let Some(state) = self.state.clone() {
+            for unit in state.units() {
+                let age = unit.age();
+
+                if age > self.expiry {
+                    state.remove(&unit.mac);
+                    continue;
+                }
+
+                // The discover rules may have changed since the unit was recorded
+                if !self.filter.allowed(&unit.discovered()) {
+                    debug!("Forgetting Daikin adaptor {} at {}", unit.mac, unit.address);
+                    state.remove(&unit.mac);
+                    continue;
+                }
+
+                info!(
+                    "Restoring Daikin adaptor {} ({}) at {}",
+                    unit.name.as_deref().unwrap_or("unnamed"),
+                    unit.mac,
+                    unit.address
+                );
+
+                // Discovery re-validates the unit, if it doesn't the unit expires as usual
+                let last_seen = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+
+                self.start_adaptor(&unit.address, Some(&unit.mac), Some(last_seen))
+                    .await;
             }
+
+            tokio::spawn(async move {
+                state.write_loop().await;
+            });
         }
 
         let mut discovered = self.discover.subscribe();
@@ -60,31 +165,236 @@ impl DaikinWatcher {
 
         tokio::spawn(async move {
             loop {
-                let address = discovered.recv().await.unwrap();
-
-                this.start_adaptor(&address).await;
+                match discovered.recv().await {
+                    Ok(unit) => this.discovered(unit).await,
+                    // Units answer every discover request so the missed ones will be seen again
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!(
+                            "Missed {} discovered Daikin adaptors, falling behind",
+                            missed
+                        )
+                    }
+                    Err(RecvError::Closed) => return,
+                }
             }
         });
+
+        let this = self.clone();
+
+        tokio::spawn(async move {
+            this.expire_loop().await;
+        });
+    }
+
+    // Stops polling adaptors that have disappeared from the network and warns about units sharing
+    // a device label
+    async fn expire_loop(&self) {
+        let mut interval = interval(self.configuration.refresh_interval());
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        // Device labels already warned about
+        let mut warned = HashSet::new();
+
+        loop {
+            interval.tick().await;
+
+            let mut adaptors = self.adaptors.lock().await;
+
+            let expired: Vec<String> = adaptors
+                .iter()
+                .filter(|(_, watched)| watched.expired(self.expiry))
+                .map(|(key, _)| key.clone())
+                .collect();
+
+            for key in expired {
+                let watched = adaptors.remove(&key).unwrap();
+
+                watched.task.abort();
+                watched.adaptor.remove_metrics();
+
+                if let (Some(state), Some(mac)) = (&self.state, watched.adaptor.mac()) {
+                    state.remove(&mac);
+                }
+
+                // Expired adaptors are always discovered ones, which have a last_seen
+                let unseen = watched
+                    .last_seen
+                    .map(|seen| seen.elapsed())
+                    .unwrap_or_default();
+
+                info!(
+                    "Removed Daikin adaptor {} at {}, not seen for {:?}",
+                    key,
+                    watched.adaptor.host(),
+                    unseen
+                );
+
+                REMOVED.inc();
+            }
+
+            warn_collisions(&adaptors, &mut warned);
+        }
+    }
+
+    async fn discovered(&self, unit: DiscoveredUnit) {
+        debug!(
+            "Discovered Daikin adaptor {} ({}) at {} on {}, version {}, UDP port {}",
+            unit.name.as_deref().unwrap_or("unnamed"),
+            unit.mac.as_deref().unwrap_or("unknown MAC"),
+            unit.address,
+            unit.interface.as_deref().unwrap_or("unknown interface"),
+            unit.version.as_deref().unwrap_or("unknown"),
+            unit.port
+                .map_or("unknown".to_string(), |port| port.to_string())
+        );
+
+        if !self.filter.allowed(&unit) {
+            debug!("Ignoring Daikin adaptor at {}", unit.address);
+            return;
+        }
+
+        if let Some(state) = &self.state {
+            state.seen(&unit);
+        }
+
+        self.start_adaptor(&unit.address, unit.mac.as_deref(), Some(Instant::now()))
+            .await;
     }
 
-    async fn start_adaptor(&self, host: &str) {
+    // `last_seen` is when discovery last saw the unit, None for configured hosts
+    async fn start_adaptor(&self, host: &str, mac: Option<&str>, last_seen: Option<Instant>) {
+        let mac = mac.map(normalize_mac);
         let mut adaptors = self.adaptors.lock().await;
 
-        if adaptors.contains_key(host) {
+        if let Some(mac) = &mac {
+            let existing = adaptors
+                .values_mut()
+                .find(|watched| watched.adaptor.mac().as_ref() == Some(mac));
+
+            if let Some(watched) = existing {
+                let old_host = watched.adaptor.host();
+
+                if old_host != host {
+                    info!("Daikin adaptor {} moved from {} to {}", mac, old_host, host);
+                    watched.adaptor.set_host(host);
+                }
+
+                watched.seen();
+
+                return;
+            }
+        }
+
+        // A unit at this host whose MAC isn't known yet, such as a configured host that hasn't
+        // been polled, is the same unit.
+        let existing = adaptors.values_mut().find(|watched| {
+            watched.adaptor.host() == host && (mac.is_none() || watched.adaptor.mac().is_none())
+        });
+
+        if let Some(watched) = existing {
+            // The MAC selects the `[[host]]` settings, such as the key, before the unit answers
+            if let Some(mac) = &mac {
+                watched.adaptor.set_mac(mac);
+            }
+
+            watched.seen();
+
             return;
         }
 
         info!("Watching Daikin adaptor {}", host);
 
-        let daikin_adaptor = DaikinAdaptor::new(host.to_string(), self.interval);
+        let key = mac.clone().unwrap_or_else(|| host.to_string());
+
+        let daikin_adaptor = DaikinAdaptor::new(
+            host.to_string(),
+            mac,
+            self.configuration.clone(),
+            self.labels.clone(),
+            self.custom_metrics.clone(),
+            self.discover.clone(),
+        );
 
         let client = self.client.clone();
         let mut adaptor = daikin_adaptor.clone();
 
-        tokio::spawn(async move {
+        let task = tokio::spawn(async move {
             adaptor.read_loop(client).await;
         });
 
-        adaptors.insert(host.to_string(), daikin_adaptor);
+        let watched = Watched {
+            adaptor: daikin_adaptor,
+            task,
+            last_seen,
+        };
+
+        adaptors.insert(key, watched);
+    }
+}
+
+// Warns once about each device label shared by more than one unit, such as two units with the same
+// name when device_identity is "name".  Their series overwrite each other.
+fn warn_collisions(adaptors: &Adaptors, warned: &mut HashSet<String>) {
+    let devices = adaptors
+        .iter()
+        .filter_map(|(key, watched)| Some((watched.adaptor.device_id()?, key.clone())));
+
+    let collisions = collisions(devices);
+
+    // Warn again if a collision that went away comes back
+    warned.retain(|id| collisions.contains_key(id));
+
+    for (id, keys) in collisions {
+        if warned.insert(id.clone()) {
+            warn!(
+                "Daikin adaptors {} share the device label {}, their metrics will overwrite each other",
+                keys.join(", "),
+                id
+            );
+        }
+    }
+}
+
+// Groups `devices`, pairs of device label and adaptor, by label keeping the labels with more than
+// one adaptor
+fn collisions(devices: impl Iterator<Item = (String, String)>) -> HashMap<String, Vec<String>> {
+    let mut adaptors: HashMap<String, Vec<String>> = HashMap::new();
+
+    for (id, adaptor) in devices {
+        adaptors.entry(id).or_default().push(adaptor);
+    }
+
+    adaptors.retain(|_, adaptors| adaptors.len() > 1);
+
+    for adaptors in adaptors.values_mut() {
+        adaptors.sort();
+    }
+
+    adaptors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colliding_devices() {
+        let devices = vec![
+            ("Lounge", "0000000E2E02"),
+            ("Bedroom", "0000000E2E03"),
+            ("Lounge", "0000000E2E01"),
+        ];
+
+        let collisions = collisions(
+            devices
+                .into_iter()
+                .map(|(id, adaptor)| (id.to_string(), adaptor.to_string())),
+        );
+
+        assert_eq!(1, collisions.len());
+        assert_eq!(
+            vec!["0000000E2E01".to_string(), "0000000E2E02".to_string()],
+            collisions["Lounge"]
+        );
     }
 }
@@ -0,0 +1,392 @@
+use anyhow::Context;
+use anyhow::Result;
+
+use crate::configuration::normalize_mac;
+use crate::configuration::Configuration;
+use crate::configuration::DiscoverRule;
+use crate::daikin_discover::DiscoveredUnit;
+
+use glob::Pattern;
+
+use ipnet::IpNet;
+
+use lazy_static::lazy_static;
+
+use prometheus::register_int_counter_vec;
+use prometheus::IntCounterVec;
+
+use std::net::IpAddr;
+
+lazy_static! {
+    static ref REJECTED: IntCounterVec = register_int_counter_vec!(
+        "daikin_udp_discover_rejected_total",
+        "Number of discovered Daikin adaptors rejected by the allow and deny rules",
+        &["host", "mac", "reason"],
+    )
+    .unwrap();
+}
+
+// A compiled allow or deny rule.  Every condition that is set must match.
+
+struct Rule {
+    mac: Option<String>,
+    name: Option<Pattern>,
+    address: Option<IpNet>,
+    adaptor_type: Option<String>,
+}
+
+impl Rule {
+    fn new(rule: &DiscoverRule) -> Result<Self> {
+        let mac = rule.mac.as_deref().map(normalize_mac);
+
+        let name = match &rule.name {
+            Some(name) => Some(
+                Pattern::new(name)
+                    .with_context(|| format!("Invalid discover rule name pattern {}", name))?,
+            ),
+            None => None,
+        };
+
+        let address = match &rule.address {
+            Some(address) => Some(parse_net(address)?),
+            None => None,
+        };
+
+        let adaptor_type = rule.adaptor_type.clone();
+
+        Ok(Rule {
+            mac,
+            name,
+            address,
+            adaptor_type,
+        })
+    }
+
+    fn matches(&self, unit: &DiscoveredUnit) -> bool {
+        if let Some(mac) = &self.mac {
+            if unit.mac.as_ref() != Some(mac) {
+                return false;
+            }
+        }
+
+        if let Some(name) = &self.name {
+            match &unit.name {
+                Some(n) if name.matches(n) => (),
+                _ => return false,
+            }
+        }
+
+        if let Some(address) = &self.address {
+            match unit.address.parse::<IpAddr>() {
+                Ok(ip) if address.contains(&ip) => (),
+                _ => return false,
+            }
+        }
+
+        if let Some(adaptor_type) = &self.adaptor_type {
+            if unit.adaptor_type.as_ref() != Some(adaptor_type) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+// Filters discovered units by the `discover_allow` and `discover_deny` rules.  When there are
+// allow rules a unit must match one of them.  A unit matching any deny rule is rejected.
+
+pub struct DiscoverFilter {
+    allow: Vec<Rule>,
+    deny: Vec<Rule>,
+    // Configured host addresses, which are polled whatever the rules say
+    hosts: Vec<String>,
+}
+
+impl DiscoverFilter {
+    pub fn new(configuration: &Configuration) -> Result<Self> {
+        let allow = configuration
+            .discover_allow()
+            .iter()
+            .map(Rule::new)
+            .collect::<Result<_>>()?;
+
+        let deny = configuration
+            .discover_deny()
+            .iter()
+            .map(Rule::new)
+            .collect::<Result<_>>()?;
+
+        let hosts = configuration.hosts().unwrap_or_default();
+
+        Ok(DiscoverFilter { allow, deny, hosts })
+    }
+
+    // Returns true if `unit` may be polled.  Rejected units are counted unless they are configured
+    // hosts, which are polled anyway.
+    pub fn allowed(&self, unit: &DiscoveredUnit) -> bool {
+        let reason = if !self.allow.is_empty() && !self.allow.iter().any(|r| r.matches(unit)) {
+            "not_allowed"
+        } else if self.deny.iter().any(|r| r.matches(unit)) {
+            "denied"
+        } else {
+            return true;
+        };
+
+        if self.hosts.contains(&unit.address) {
+            return false;
+        }
+
+        let mac = unit.mac.as_deref().unwrap_or("");
+
+        REJECTED
+            .with_label_values(&[&unit.address, mac, reason])
+            .inc();
+
+        false
+    }
+}
+
+// Selects the network interfaces discover requests are broadcast from by the
+// `discover_interfaces` and `discover_exclude_interfaces` name globs
+
+#[derive(Clone)]
+pub struct InterfaceFilter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl InterfaceFilter {
+    pub fn new(configuration: &Configuration) -> Result<Self> {
+        let include = compile_patterns(configuration.discover_interfaces())?;
+        let exclude = compile_patterns(configuration.discover_exclude_interfaces())?;
+
+        Ok(InterfaceFilter { include, exclude })
+    }
+
+    // When no interfaces are included all interfaces not excluded are used
+    pub fn matches(&self, interface: &str) -> bool {
+        let included = self.include.is_empty() || self.include.iter().any(|p| p.matches(interface));
+
+        included && !self.exclude.iter().any(|p| p.matches(interface))
+    }
+}
+
+fn compile_patterns(patterns: &[String]) -> Result<Vec<Pattern>> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            Pattern::new(pattern).with_context(|| format!("Invalid interface pattern {}", pattern))
+        })
+        .collect()
+}
+
+// Parses "10.0.0.0/24" or a single address like "10.0.0.5"
+pub fn parse_net(address: &str) -> Result<IpNet> {
+    if let Ok(net) = address.parse::<IpNet>() {
+        return Ok(net);
+    }
+
+    let ip = address
+        .parse::<IpAddr>()
+        .with_context(|| format!("Invalid address or CIDR {}", address))?;
+
+    Ok(IpNet::from(ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit(address: &str, mac: &str, name: &str, adaptor_type: &str) -> DiscoveredUnit {
+        let body = format!(
+            "ret=OK,type={},name={},mac={}",
+            adaptor_type,
+            crate::daikin_adaptor::percent_encode(name),
+            mac
+        );
+
+        DiscoveredUnit::parse(address.to_string(), &body)
+    }
+
+    fn configuration(source: &str) -> Configuration {
+        toml::from_str(source).unwrap()
+    }
+
+    #[test]
+    fn rule_matches() {
+        let bedroom = unit("10.101.28.64", "60F189B4B2D0", "Bedroom", "aircon");
+
+        let cases = [
+            (r#"mac = "60:f1:89:b4:b2:d0""#, true),
+            (r#"mac = "60F189B46407""#, false),
+            (r#"name = "Bed*""#, true),
+            (r#"name = "Office""#, false),
+            (r#"address = "10.101.28.0/24""#, true),
+            (r#"address = "10.101.28.64""#, true),
+            (r#"address = "10.101.29.0/24""#, false),
+            (r#"adaptor_type = "aircon""#, true),
+            (r#"adaptor_type = "skyfi""#, false),
+            ("", true),
+            (
+                r#"name = "Bed*"
+                   address = "10.101.28.0/24""#,
+                true,
+            ),
+            (
+                r#"name = "Bed*"
+                   address = "10.101.29.0/24""#,
+                false,
+            ),
+        ];
+
+        for (source, expected) in cases {
+            let rule: DiscoverRule = toml::from_str(source).unwrap();
+            let rule = Rule::new(&rule).unwrap();
+
+            assert_eq!(expected, rule.matches(&bedroom), "{}", source);
+        }
+    }
+
+    #[test]
+    fn rule_invalid() {
+        let cases = [
+            r#"name = "[""#,
+            r#"address = "10.101.28.0/33""#,
+            r#"address = "x""#,
+        ];
+
+        for source in cases {
+            let rule: DiscoverRule = toml::from_str(source).unwrap();
+
+            assert!(Rule::new(&rule).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn filter_allowed() {
+        let bedroom = unit("10.101.28.64", "60F189B4B2D0", "Bedroom", "aircon");
+        let office = unit("10.101.28.65", "60F189B46407", "Music office", "aircon");
+        let neighbour = unit("10.101.29.10", "60F189000001", "Neighbour", "aircon");
+
+        let cases = [
+            ("", [true, true, true]),
+            (
+                r#"[[discover_allow]]
+                   address = "10.101.28.0/24""#,
+                [true, true, false],
+            ),
+            (
+                r#"[[discover_deny]]
+                   name = "Neighbour*""#,
+                [true, true, false],
+            ),
+            (
+                r#"[[discover_allow]]
+                   address = "10.101.28.0/24"
+
+                   [[discover_deny]]
+                   mac = "60F189B46407""#,
+                [true, false, false],
+            ),
+            (
+                r#"[[discover_allow]]
+                   name = "Bedroom"
+
+                   [[discover_allow]]
+                   name = "Neighbour""#,
+                [true, false, true],
+            ),
+        ];
+
+        for (source, expected) in cases {
+            let filter = DiscoverFilter::new(&configuration(source)).unwrap();
+
+            let allowed = [
+                filter.allowed(&bedroom),
+                filter.allowed(&office),
+                filter.allowed(&neighbour),
+            ];
+
+            assert_eq!(expected, allowed, "{}", source);
+        }
+    }
+
+    #[test]
+    fn filter_rejected_count() {
+        let configured = unit("10.101.30.1", "60F189000002", "Configured", "aircon");
+        let denied = unit("10.101.30.2", "60F189000003", "Denied", "aircon");
+
+        let filter = DiscoverFilter::new(&configuration(
+            r#"hosts = ["10.101.30.1"]
+
+               [[discover_deny]]
+               address = "10.101.30.0/24""#,
+        ))
+        .unwrap();
+
+        assert!(!filter.allowed(&configured));
+        assert!(!filter.allowed(&denied));
+
+        let rejected = |unit: &DiscoveredUnit| {
+            REJECTED
+                .with_label_values(&[&unit.address, unit.mac.as_deref().unwrap(), "denied"])
+                .get()
+        };
+
+        assert_eq!(0, rejected(&configured));
+        assert_eq!(1, rejected(&denied));
+    }
+
+    #[test]
+    fn interface_filter() {
+        let cases = [
+            ("", [true, true, true]),
+            (r#"discover_interfaces = ["eth0"]"#, [true, false, false]),
+            (
+                r#"discover_interfaces = ["eth*", "wlan*"]"#,
+                [true, true, false],
+            ),
+            (
+                r#"discover_exclude_interfaces = ["docker*"]"#,
+                [true, true, false],
+            ),
+            (
+                r#"discover_interfaces = ["eth*"]
+                   discover_exclude_interfaces = ["eth0"]"#,
+                [false, true, false],
+            ),
+        ];
+
+        for (source, expected) in cases {
+            let filter = InterfaceFilter::new(&configuration(source)).unwrap();
+
+            let matches = [
+                filter.matches("eth0"),
+                filter.matches("eth1"),
+                filter.matches("docker0"),
+            ];
+
+            assert_eq!(expected, matches, "{}", source);
+        }
+    }
+
+    #[test]
+    fn parse_net_cases() {
+        let cases = [
+            ("10.101.28.0/24", Some("10.101.28.0/24")),
+            ("10.101.28.64", Some("10.101.28.64/32")),
+            ("fe80::/64", Some("fe80::/64")),
+            ("fe80::1", Some("fe80::1/128")),
+            ("10.101.28.0/33", None),
+            ("10.101.28", None),
+            ("", None),
+        ];
+
+        for (address, expected) in cases {
+            let net = parse_net(address).ok().map(|net| net.to_string());
+
+            assert_eq!(expected.map(String::from), net, "{}", address);
+        }
+    }
+}
@@ -1,8 +1,18 @@
 mod configuration;
+mod custom_metrics;
 mod daikin_adaptor;
+mod daikin_control;
 mod daikin_discover;
 mod daikin_exporter;
+mod daikin_hvac;
+mod daikin_schedule;
+#[cfg(test)]
+mod daikin_simulator;
+mod daikin_state;
 mod daikin_watcher;
+mod discover_filter;
+#[cfg(test)]
+mod pcap_replay;
 
 use configuration::Configuration;
 use daikin_discover::DaikinDiscover;
@@ -42,7 +52,16 @@ async fn main() -> Result<()> {
 
     Builder::from_env(Env::default().default_filter_or("info")).init();
 
-    let configuration = Configuration::load_from_next_arg();
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+
+    let configuration = match args.first() {
+        Some(arg) if !daikin_control::is_command(arg) => Configuration::load(args.remove(0)),
+        _ => Configuration::default(),
+    };
+
+    if !args.is_empty() {
+        return daikin_control::run(configuration, &args).await;
+    }
 
     let (error_tx, error_rx) = mpsc::channel(1);
 
@@ -51,7 +70,7 @@ async fn main() -> Result<()> {
         .start(error_tx.clone())
         .await;
 
-    let mut watcher = DaikinWatcher::new(discover, &configuration);
+    let mut watcher = DaikinWatcher::new(discover, &configuration)?;
     watcher.start().await;
 
     DaikinExporter::new(configuration.bind_address())?
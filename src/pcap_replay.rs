@@ -0,0 +1,402 @@
+// Replays the Daikin traffic in daikin.pcap, captured from the ComfortControl app, against the
+// response parsers and metrics so protocol regressions are caught without hardware.
+
+use crate::configuration::Configuration;
+use crate::daikin_adaptor::parse_response;
+use crate::daikin_adaptor::record_response;
+use crate::daikin_discover::DiscoveredUnit;
+
+use prometheus::proto::MetricType;
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::net::Ipv4Addr;
+
+const CAPTURE: &[u8] = include_bytes!("../daikin.pcap");
+
+const LINKTYPE_ETHERNET: u32 = 1;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+
+// A UDP datagram
+struct Datagram {
+    source: Ipv4Addr,
+    destination_port: u16,
+    payload: Vec<u8>,
+}
+
+// An HTTP request and its response body
+struct Exchange {
+    host: Ipv4Addr,
+    // Path without the leading "/" or query, like "aircon/get_sensor_info"
+    path: String,
+    query: String,
+    body: String,
+}
+
+// One direction of a TCP connection
+type Flow = (Ipv4Addr, u16, Ipv4Addr, u16);
+
+struct Capture {
+    datagrams: Vec<Datagram>,
+    exchanges: Vec<Exchange>,
+}
+
+impl Capture {
+    // Parses a little-endian pcap file of ethernet frames
+    fn parse(capture: &[u8]) -> Self {
+        assert_eq!(0xa1b2c3d4, u32_le(capture, 0), "not a little-endian pcap");
+        assert_eq!(LINKTYPE_ETHERNET, u32_le(capture, 20), "not ethernet");
+
+        let mut datagrams = Vec::new();
+        let mut segments: HashMap<Flow, BTreeMap<u32, Vec<u8>>> = HashMap::new();
+        let mut flows = Vec::new();
+
+        let mut offset = 24;
+
+        while offset + 16 <= capture.len() {
+            let captured = u32_le(capture, offset + 8) as usize;
+            let frame = &capture[offset + 16..offset + 16 + captured];
+            offset += 16 + captured;
+
+            if u16_be(frame, 12) != ETHERTYPE_IPV4 {
+                continue;
+            }
+
+            let ip = &frame[14..];
+            let header_length = ((ip[0] & 0x0f) * 4) as usize;
+            // Short frames are padded so trust the IP length rather than the frame length
+            let total_length = u16_be(ip, 2) as usize;
+            let protocol = ip[9];
+            let source = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+            let destination = Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]);
+            let transport = &ip[header_length..total_length];
+
+            let source_port = u16_be(transport, 0);
+            let destination_port = u16_be(transport, 2);
+
+            match protocol {
+                PROTOCOL_UDP => datagrams.push(Datagram {
+                    source,
+                    destination_port,
+                    payload: transport[8..].to_vec(),
+                }),
+                PROTOCOL_TCP => {
+                    let sequence = u32_be(transport, 4);
+                    let data_offset = ((transport[12] >> 4) * 4) as usize;
+                    let payload = &transport[data_offset..];
+
+                    if payload.is_empty() {
+                        continue;
+                    }
+
+                    let flow = (source, source_port, destination, destination_port);
+
+                    if !segments.contains_key(&flow) {
+                        flows.push(flow);
+                    }
+
+                    // Retransmissions share a sequence number
+                    segments
+                        .entry(flow)
+                        .or_default()
+                        .insert(sequence, payload.to_vec());
+                }
+                _ => (),
+            }
+        }
+
+        let streams: HashMap<Flow, Vec<u8>> = segments
+            .into_iter()
+            .map(|(flow, segments)| (flow, segments.into_values().flatten().collect()))
+            .collect();
+
+        let mut exchanges = Vec::new();
+
+        for flow in flows {
+            let (client, client_port, server, server_port) = flow;
+
+            if server_port != 80 {
+                continue;
+            }
+
+            let request = String::from_utf8_lossy(&streams[&flow]).to_string();
+            let reply = match streams.get(&(server, server_port, client, client_port)) {
+                Some(r) => String::from_utf8_lossy(r).to_string(),
+                None => continue,
+            };
+
+            let target = request
+                .lines()
+                .next()
+                .and_then(|line| line.split(' ').nth(1))
+                .unwrap();
+
+            let (path, query) = target.split_once('?').unwrap_or((target, ""));
+            let (_, body) = reply.split_once("\r\n\r\n").unwrap();
+
+            exchanges.push(Exchange {
+                host: server,
+                path: path.trim_start_matches('/').to_string(),
+                query: query.to_string(),
+                body: body.to_string(),
+            });
+        }
+
+        Capture {
+            datagrams,
+            exchanges,
+        }
+    }
+
+    // Discover responses, which are sent back to the app's port rather than 30050
+    fn discover_responses(&self) -> Vec<DiscoveredUnit> {
+        self.datagrams
+            .iter()
+            .filter(|datagram| datagram.destination_port != 30050)
+            .map(|datagram| {
+                let body = String::from_utf8_lossy(&datagram.payload);
+
+                DiscoveredUnit::parse(datagram.source.to_string(), &body)
+            })
+            .collect()
+    }
+
+    fn exchanges_for(&self, path: &str) -> Vec<&Exchange> {
+        self.exchanges
+            .iter()
+            .filter(|exchange| exchange.path == path)
+            .collect()
+    }
+}
+
+fn u16_be(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_be(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn u32_le(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+// Value of the gauge or counter `name` with `labels` from the default registry
+pub fn metric_value(name: &str, labels: &[(&str, &str)]) -> Option<f64> {
+    let family = prometheus::gather()
+        .into_iter()
+        .find(|family| family.get_name() == name)?;
+
+    let metric = family.get_metric().iter().find(|metric| {
+        labels.iter().all(|(name, value)| {
+            metric
+                .get_label()
+                .iter()
+                .any(|label| label.get_name() == *name && label.get_value() == *value)
+        })
+    })?;
+
+    match family.get_field_type() {
+        MetricType::COUNTER => Some(metric.get_counter().get_value()),
+        _ => Some(metric.get_gauge().get_value()),
+    }
+}
+
+// Replays every HTTP exchange in the capture using the units found by discovery for the device
+// labels
+fn replay(capture: &Capture) {
+    let units: HashMap<String, DiscoveredUnit> = capture
+        .discover_responses()
+        .into_iter()
+        .map(|unit| (unit.address.clone(), unit))
+        .collect();
+
+    for exchange in &capture.exchanges {
+        let unit = &units[&exchange.host.to_string()];
+        let device = [unit.mac.as_deref().unwrap(), unit.name.as_deref().unwrap()];
+
+        record_response(
+            &Configuration::default(),
+            &exchange.path,
+            device,
+            &parse_response(&exchange.body),
+        );
+    }
+}
+
+#[test]
+fn discover_requests() {
+    let capture = Capture::parse(CAPTURE);
+
+    let requests: Vec<&Datagram> = capture
+        .datagrams
+        .iter()
+        .filter(|datagram| datagram.destination_port == 30050)
+        .collect();
+
+    assert_eq!(6, requests.len());
+
+    for request in requests {
+        assert_eq!(b"DAIKIN_UDP/common/basic_info", &request.payload[..]);
+    }
+}
+
+#[test]
+fn discover_responses() {
+    let capture = Capture::parse(CAPTURE);
+
+    let units = capture.discover_responses();
+
+    assert_eq!(11, units.len());
+
+    let bedroom = units
+        .iter()
+        .find(|unit| unit.address == "10.101.28.64")
+        .unwrap();
+
+    assert_eq!(Some("60F189B4B2D0"), bedroom.mac.as_deref());
+    assert_eq!(Some("Bedroom"), bedroom.name.as_deref());
+    assert_eq!(Some("2_9_0"), bedroom.version.as_deref());
+    assert_eq!(Some("aircon"), bedroom.adaptor_type.as_deref());
+    assert_eq!(Some(30054), bedroom.port);
+
+    let office = units
+        .iter()
+        .find(|unit| unit.address == "10.101.28.65")
+        .unwrap();
+
+    assert_eq!(Some("60F189B46407"), office.mac.as_deref());
+    assert_eq!(Some("Music office"), office.name.as_deref());
+    assert_eq!(Some(30051), office.port);
+}
+
+#[test]
+fn http_exchanges() {
+    let capture = Capture::parse(CAPTURE);
+
+    let paths: Vec<&str> = capture
+        .exchanges
+        .iter()
+        .map(|exchange| exchange.path.as_str())
+        .collect();
+
+    assert_eq!(
+        vec![
+            "common/get_progsum",
+            "common/get_progsum",
+            "common/get_datetime",
+            "common/notify_date_time",
+            "common/get_datetime",
+            "common/notify_date_time",
+            "aircon/get_model_info",
+            "aircon/get_model_info",
+            "aircon/get_sensor_info",
+            "aircon/get_sensor_info",
+            "aircon/get_control_info",
+            "aircon/get_control_info",
+            "common/basic_info",
+            "common/basic_info",
+        ],
+        paths
+    );
+
+    let progsum = capture.exchanges_for("common/get_progsum");
+    assert_eq!("fwsize=487320", progsum[0].query);
+    assert_eq!("36085", parse_response(&progsum[0].body)["csum"]);
+
+    let notify = capture.exchanges_for("common/notify_date_time");
+    assert_eq!("THROUGH", parse_response(&notify[0].body)["ret"]);
+}
+
+#[test]
+fn basic_info_matches_discovery() {
+    let capture = Capture::parse(CAPTURE);
+    let units = capture.discover_responses();
+
+    for exchange in capture.exchanges_for("common/basic_info") {
+        let unit = units
+            .iter()
+            .find(|unit| unit.address == exchange.host.to_string())
+            .unwrap();
+
+        let discovered = DiscoveredUnit::parse(unit.address.clone(), &exchange.body);
+
+        assert_eq!(unit.mac, discovered.mac);
+        assert_eq!(unit.name, discovered.name);
+    }
+}
+
+#[test]
+fn replay_metrics() {
+    let capture = Capture::parse(CAPTURE);
+    replay(&capture);
+
+    let bedroom = [("device", "60F189B4B2D0"), ("name", "Bedroom")];
+    let office = [("device", "60F189B46407"), ("name", "Music office")];
+
+    assert_eq!(Some(1.0), metric_value("daikin_power_on", &bedroom));
+    assert_eq!(Some(0.0), metric_value("daikin_power_on", &office));
+
+    assert_eq!(
+        Some(16.5),
+        metric_value("daikin_set_temperature_degrees", &bedroom)
+    );
+    assert_eq!(
+        Some(25.0),
+        metric_value("daikin_set_temperature_degrees", &office)
+    );
+
+    assert_eq!(Some(4.0), metric_value("daikin_mode", &bedroom));
+    assert_eq!(Some(7.0), metric_value("daikin_mode", &office));
+    assert_eq!(Some(1.0), metric_value("daikin_fan_rate", &bedroom));
+    assert_eq!(Some(0.0), metric_value("daikin_fan_direction", &bedroom));
+
+    let bedroom_mode = |mode| {
+        metric_value(
+            "daikin_mode_state",
+            &[("device", "60F189B4B2D0"), ("mode", mode)],
+        )
+    };
+
+    assert_eq!(Some(1.0), bedroom_mode("heat"));
+    assert_eq!(Some(0.0), bedroom_mode("cool"));
+    assert_eq!(
+        Some(1.0),
+        metric_value(
+            "daikin_mode_state",
+            &[("device", "60F189B46407"), ("mode", "auto")]
+        )
+    );
+    assert_eq!(
+        Some(1.0),
+        metric_value(
+            "daikin_fan_rate_state",
+            &[("device", "60F189B4B2D0"), ("fan_rate", "auto")]
+        )
+    );
+
+    assert_eq!(
+        Some(20.0),
+        metric_value("daikin_unit_temperature_degrees", &bedroom)
+    );
+    assert_eq!(
+        Some(19.0),
+        metric_value("daikin_unit_temperature_degrees", &office)
+    );
+    assert_eq!(
+        Some(5.0),
+        metric_value("daikin_outdoor_temperature_degrees", &bedroom)
+    );
+    // The office unit reports "-" for the outdoor temperature
+    assert_eq!(
+        None,
+        metric_value("daikin_outdoor_temperature_degrees", &office)
+    );
+    assert_eq!(
+        Some(38.0),
+        metric_value("daikin_compressor_demand_percent", &bedroom)
+    );
+}